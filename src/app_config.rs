@@ -1,11 +1,25 @@
 use crate::download::*;
+use crate::encoder::*;
+use crate::header_rules::{self, HeaderRule, HeaderRuleConfig};
+use crate::host_filter::HostPattern;
+use crate::limiter::DownloadLimiter;
+use crate::proxy::{self, ProxyOptions};
+use crate::result_cache::ResultCache;
+use crate::retry::RetryPolicy;
+use crate::signature::UrlSigner;
+use crate::single_flight::SingleFlight;
+use crate::sniff::InputFormat;
+use crate::source_index::SourceIndex;
 use crate::storage::*;
 use crate::thumbnail::*;
 use crate::thumbnail_handler::*;
 use actix_web::web;
 use config::{Config, ConfigError, Environment, File};
-use reqwest::r#async::Client;
-use serde::Deserialize;
+use ipnet::IpNet;
+use reqwest::header::HeaderValue;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -14,14 +28,89 @@ pub struct AppConfig {
     pub listen_port: String,
     pub shutdown_timeout: u64,
     pub max_content_length: Option<u64>,
-    pub check_mime_type: bool,
+    pub content_type_check: ContentTypeCheck,
+    /// Formats of downloaded images, any format if empty.
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub allowed_input_formats: Vec<String>,
+    #[serde(default)]
+    pub head_request: bool,
+    /// Download attempts including the first one.
+    pub retry_max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub retry_jitter: f64,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub retry_status_codes: Vec<String>,
+    pub max_redirects: usize,
+    /// Downloads in flight across all requests, 0 for no limit.
+    pub max_downloads: usize,
+    /// Downloads in flight from a single host, 0 for no limit.
+    pub max_downloads_per_host: usize,
+    #[serde(default)]
+    pub allow_https_downgrade: bool,
+    /// Source urls whose `ETag` and `Last-Modified` are kept to download them again
+    /// only if they changed, 0 to always download them.
+    pub source_index_size: usize,
+    /// Thumbnails whose paths are kept by image url and options, to skip downloading the image.
+    pub result_cache_size: usize,
+    /// Time a cached thumbnail path is used for, 0 disables the result cache.
+    pub result_cache_ttl_secs: u64,
+    /// Private networks, in CIDR notation, images may be downloaded from.
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub allowed_networks: Vec<String>,
+    /// Hosts images may be downloaded from, `*.example.com` matches all subdomains.
+    /// Any host is allowed if empty.
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub allowed_hosts: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub denied_hosts: Vec<String>,
     pub max_urls_in_single_req: u64,
-    pub http_client_timeout: u64,
+    pub user_agent: String,
+    /// Headers, such as credentials, sent to matching image hosts.
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRuleConfig>,
+    /// Proxy images from http urls are downloaded through, none if empty.
+    #[serde(default)]
+    pub http_proxy: String,
+    /// Proxy images from https urls are downloaded through, none if empty.
+    #[serde(default)]
+    pub https_proxy: String,
+    /// Hosts images are downloaded from without the proxy, in the format of `allowed_hosts`.
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub no_proxy: Vec<String>,
+    #[serde(default)]
+    pub proxy_username: String,
+    /// Read from an environment variable or a file like header rule secrets.
+    #[serde(default)]
+    pub proxy_password: String,
+    pub connect_timeout_ms: u64,
+    /// Time until the response headers of an image are received.
+    pub first_byte_timeout_ms: u64,
+    /// Time downloading an image may take, per attempt.
+    pub download_timeout_ms: u64,
+    /// Bytes per second images must be downloaded with, 0 for no limit.
+    pub min_download_speed: u64,
     pub thumbnail_width: u32,
     pub thumbnail_height: u32,
-    pub thumbnail_exact_size: bool,
+    pub thumbnail_mode: ResizeMode,
+    pub thumbnail_background: Color,
+    pub max_thumbnail_dimension: u32,
+    #[serde(default)]
+    pub presets: HashMap<String, ThumbnailParams>,
+    pub allow_custom_sizes: bool,
     pub storage_base_dir: String,
-    pub thumbnail_extension: String,
+    pub thumbnail_format: OutputFormat,
+    pub jpeg_quality: u8,
+    pub jpeg_progressive: bool,
+    pub png_compression: PngCompression,
+    pub webp_lossless: bool,
+    pub webp_quality: u8,
+    pub avif_quality: u8,
+    pub avif_speed: u8,
+    pub negotiate_format: bool,
+    /// Keys accepted in render url signatures, render urls are not signed if empty.
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub render_signing_keys: Vec<String>,
     pub log_level: String,
 }
 
@@ -30,12 +119,57 @@ impl AppConfig {
         let mut c = Config::new();
         c.merge(File::with_name("src/default_config").required(false))?;
         c.merge(Environment::with_prefix("APP"))?;
+        // replaced by `download_timeout_ms`, fail rather than ignore it.
+        if c.get_str("http_client_timeout").is_ok() {
+            return Err(ConfigError::Message(
+                "http_client_timeout is no longer supported, set download_timeout_ms instead"
+                    .to_owned(),
+            ));
+        }
+        // replaced by `content_type_check`, which has no mode skipping the check.
+        if c.get_str("check_mime_type").is_ok() {
+            return Err(ConfigError::Message(
+                "check_mime_type is no longer supported, set content_type_check instead".to_owned(),
+            ));
+        }
         c.try_into()
     }
 }
 
-pub fn configure_app(cfg: &mut web::ServiceConfig, app_config: &AppConfig) -> std::io::Result<()> {
-    let (thumbnail, storage, downloader, handler_options) = create_services(app_config)?;
+/// State shared by the apps of all workers, created once.
+#[derive(Debug, Clone)]
+pub struct SharedState {
+    pub download_limiter: DownloadLimiter,
+    pub source_index: SourceIndex,
+    pub result_cache: ResultCache,
+    pub downloads: SingleFlight<DownloadedImage, DownloadError>,
+    pub thumbnails: SingleFlight<(), Arc<HandlerError>>,
+}
+
+impl SharedState {
+    pub fn new(app_config: &AppConfig) -> Self {
+        SharedState {
+            download_limiter: DownloadLimiter::new(
+                app_config.max_downloads,
+                app_config.max_downloads_per_host,
+            ),
+            source_index: SourceIndex::new(app_config.source_index_size),
+            result_cache: ResultCache::new(
+                app_config.result_cache_size,
+                Duration::from_secs(app_config.result_cache_ttl_secs),
+            ),
+            downloads: SingleFlight::new(),
+            thumbnails: SingleFlight::new(),
+        }
+    }
+}
+
+pub fn configure_app(
+    cfg: &mut web::ServiceConfig,
+    app_config: &AppConfig,
+    shared: &SharedState,
+) -> std::io::Result<()> {
+    let (thumbnail, storage, downloader, handler_options) = create_services(app_config, shared)?;
     cfg.data(handler_options)
         .data(thumbnail)
         .data(storage)
@@ -45,42 +179,181 @@ pub fn configure_app(cfg: &mut web::ServiceConfig, app_config: &AppConfig) -> st
 
 pub fn create_services(
     app_config: &AppConfig,
+    shared: &SharedState,
 ) -> std::io::Result<(
     ThumbnailCreator,
     ThumbnailStorage,
     Downloader,
     HandlerOptions,
 )> {
-    let thumbnail = ThumbnailCreator::new(ThumbnailOptions {
-        width: app_config.thumbnail_width,
-        height: app_config.thumbnail_width,
-        exact_size: app_config.thumbnail_exact_size,
-    });
-
-    let storage = ThumbnailStorage::new(
-        &app_config.storage_base_dir,
-        app_config.thumbnail_width,
-        app_config.thumbnail_height,
-        &app_config.thumbnail_extension,
-    )
-    .expect("failed to initialize storage");
-
-    let http_client = Client::builder()
-        .timeout(Duration::from_secs(app_config.http_client_timeout))
-        .build()
-        .expect("failed to create http client");
+    let thumbnail = ThumbnailCreator::new();
+
+    let encoder = EncoderOptions {
+        jpeg_quality: app_config.jpeg_quality,
+        jpeg_progressive: app_config.jpeg_progressive,
+        png_compression: app_config.png_compression,
+        webp_lossless: app_config.webp_lossless,
+        webp_quality: app_config.webp_quality,
+        avif_quality: app_config.avif_quality,
+        avif_speed: app_config.avif_speed,
+    };
+    encoder
+        .validate()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let storage = ThumbnailStorage::new(&app_config.storage_base_dir, encoder.clone())
+        .expect("failed to initialize storage");
+
+    let retry = RetryPolicy {
+        max_attempts: app_config.retry_max_attempts,
+        base_delay: Duration::from_millis(app_config.retry_base_delay_ms),
+        max_delay: Duration::from_millis(app_config.retry_max_delay_ms),
+        jitter: app_config.retry_jitter,
+        retryable_status_codes: app_config
+            .retry_status_codes
+            .iter()
+            .map(|code| code.parse::<u16>())
+            .collect::<Result<_, _>>()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+    };
+    retry
+        .validate()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
 
     let downloader = Downloader::new(
         DownloadOptions {
             max_content_length: app_config.max_content_length,
-            check_mime_type: app_config.check_mime_type,
+            content_type_check: app_config.content_type_check,
+            allowed_input_formats: app_config
+                .allowed_input_formats
+                .iter()
+                .map(|format| format.parse::<InputFormat>())
+                .collect::<Result<_, _>>()
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+            head_request: app_config.head_request,
+            retry,
+            first_byte_timeout: Duration::from_millis(app_config.first_byte_timeout_ms),
+            download_timeout: Duration::from_millis(app_config.download_timeout_ms),
+            min_download_speed: app_config.min_download_speed,
+            connect_timeout: Duration::from_millis(app_config.connect_timeout_ms),
+            user_agent: HeaderValue::from_str(&app_config.user_agent)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+            proxy: create_proxy(app_config)?,
+            header_rules: app_config
+                .header_rules
+                .iter()
+                .map(HeaderRule::from_config)
+                .collect::<Result<_, _>>()
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+            max_redirects: app_config.max_redirects,
+            allow_https_downgrade: app_config.allow_https_downgrade,
+            allowed_networks: app_config
+                .allowed_networks
+                .iter()
+                .map(|network| network.parse::<IpNet>())
+                .collect::<Result<_, _>>()
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+            allowed_hosts: parse_host_patterns(&app_config.allowed_hosts)?,
+            denied_hosts: parse_host_patterns(&app_config.denied_hosts)?,
         },
-        http_client,
+        shared.download_limiter.clone(),
     );
 
     let handler_options = HandlerOptions {
         max_url_in_single_req: app_config.max_urls_in_single_req,
+        max_thumbnail_dimension: app_config.max_thumbnail_dimension,
+        default_thumbnail: ThumbnailOptions {
+            width: app_config.thumbnail_width,
+            height: app_config.thumbnail_height,
+            mode: app_config.thumbnail_mode,
+            background: app_config.thumbnail_background,
+            gravity: Gravity::Center,
+            focal_point: None,
+            format: app_config.thumbnail_format,
+            encoder,
+        },
+        presets: app_config.presets.clone(),
+        allow_custom_sizes: app_config.allow_custom_sizes,
+        negotiated_formats: if app_config.negotiate_format {
+            vec![OutputFormat::Avif, OutputFormat::Webp]
+        } else {
+            vec![]
+        },
+        signer: if app_config.render_signing_keys.is_empty() {
+            None
+        } else {
+            Some(UrlSigner::new(&app_config.render_signing_keys))
+        },
+        source_index: shared.source_index.clone(),
+        result_cache: shared.result_cache.clone(),
+        downloads: shared.downloads.clone(),
+        thumbnails: shared.thumbnails.clone(),
     };
 
     Ok((thumbnail, storage, downloader, handler_options))
 }
+
+fn create_proxy(app_config: &AppConfig) -> std::io::Result<ProxyOptions> {
+    let parse_url = |url: &str| {
+        if url.is_empty() {
+            return Ok(None);
+        }
+        proxy::parse_proxy_url(url)
+            .map(Some)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+    };
+    let credentials = if app_config.proxy_username.is_empty() {
+        None
+    } else {
+        let password = header_rules::resolve_secret(&app_config.proxy_password)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        Some((app_config.proxy_username.clone(), password))
+    };
+    Ok(ProxyOptions {
+        http: parse_url(&app_config.http_proxy)?,
+        https: parse_url(&app_config.https_proxy)?,
+        no_proxy: parse_host_patterns(&app_config.no_proxy)?,
+        credentials,
+    })
+}
+
+fn parse_host_patterns(patterns: &[String]) -> std::io::Result<Vec<HostPattern>> {
+    patterns
+        .iter()
+        .map(|pattern| pattern.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+}
+
+/// Deserializes a list given either as an array or, in environment variables, as a
+/// comma separated string.
+fn deserialize_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    struct ListVisitor;
+
+    impl<'de> de::Visitor<'de> for ListVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a list or a comma separated string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect())
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut items = vec![];
+            while let Some(item) = seq.next_element()? {
+                items.push(item);
+            }
+            Ok(items)
+        }
+    }
+
+    deserializer.deserialize_any(ListVisitor)
+}
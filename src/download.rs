@@ -1,144 +1,697 @@
+use crate::header_rules::{self, HeaderRule};
+use crate::host_filter::{HostFilter, HostPattern};
+use crate::ip_filter::{IpFilter, IpFilterError};
+use crate::limiter::DownloadLimiter;
+use crate::proxy::ProxyOptions;
+use crate::retry::{self, RetryPolicy};
+use crate::sniff::{self, InputFormat};
+use actix_web::{error, web};
 use bytes::Bytes;
-use failure::Fail;
+use failure::{Compat, Fail};
 use futures::future::*;
 use futures::stream::*;
+use futures::{Async, Poll};
+use hyper::client::connect::{Connect, Connected, Destination, HttpConnector};
+use hyper_tls::HttpsConnector;
+use ipnet::IpNet;
 use log::*;
-use reqwest::r#async::Client;
-use url::Url;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, USER_AGENT};
+use reqwest::r#async::{Body, Client, Response};
+use reqwest::{Method, RedirectPolicy, StatusCode};
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_timer::{Delay, Timeout};
+use url::{Host, Url};
 
 pub trait DownloadService {
-    fn download_image(&self, url: String) -> Box<dyn Future<Item = Bytes, Error = DownloadError>>;
+    /// Downloads the image unless it is unchanged since it was downloaded with `validators`.
+    fn download_if_modified(
+        &self,
+        url: String,
+        validators: Validators,
+    ) -> Box<dyn Future<Item = DownloadedImage, Error = DownloadError>>;
+
+    fn download_image(
+        &self,
+        url: String,
+    ) -> Box<dyn Future<Item = DownloadedImage, Error = DownloadError>> {
+        self.download_if_modified(url, Validators::default())
+    }
 }
 
+#[derive(Debug, Clone)]
+pub struct DownloadedImage {
+    /// Empty if the image was not modified.
+    pub bytes: Bytes,
+    /// Url the image was downloaded from, if the request was redirected.
+    pub final_url: Option<String>,
+    pub validators: Validators,
+    /// The image is unchanged since it was downloaded with the validators it was requested with.
+    pub not_modified: bool,
+}
+
+/// `ETag` and `Last-Modified` of a downloaded image, sent back as `If-None-Match` and
+/// `If-Modified-Since` to download it again only if it changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_response(res: &Response) -> Self {
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned())
+        };
+        Validators {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn conditional_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let conditions = vec![
+            (reqwest::header::IF_NONE_MATCH, &self.etag),
+            (reqwest::header::IF_MODIFIED_SINCE, &self.last_modified),
+        ];
+        for (name, value) in conditions {
+            if let Some(value) = value.as_ref().and_then(|value| value.parse().ok()) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+}
+
+/// Client of requests sent straight to image hosts. It connects only to the addresses
+/// the ip filter resolved and allowed, so a host can't be resolved to another address
+/// between the check and the connection.
+type DirectClient = hyper::Client<ConnectTimeout<HttpsConnector<HttpConnector<IpFilter>>>>;
+
 #[derive(Debug, Clone)]
 pub struct Downloader {
     opt: DownloadOptions,
-    client: Client,
+    direct: DirectClient,
+    /// Client of requests sent through the proxy, if one is configured.
+    proxied: Option<Client>,
+    host_filter: HostFilter,
+    ip_filter: IpFilter,
+    limiter: DownloadLimiter,
 }
 
 const MIME_PREFIX: &'static str = "image/";
+/// Time after the response headers before the minimum download speed is enforced.
+const SPEED_CHECK_GRACE: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone)]
 pub struct DownloadOptions {
     pub max_content_length: Option<u64>,
-    pub check_mime_type: bool,
+    pub content_type_check: ContentTypeCheck,
+    /// Formats of downloaded images, any format if empty. Checked against the format
+    /// `content_type_check` found: from the header in `Header` mode, from the content otherwise.
+    pub allowed_input_formats: Vec<InputFormat>,
+    pub max_redirects: usize,
+    /// Follow redirects from https to http urls.
+    pub allow_https_downgrade: bool,
+    pub retry: RetryPolicy,
+    /// Time from sending a request until the response headers are received.
+    pub first_byte_timeout: Duration,
+    /// Time downloading an image may take, including retries, redirects and the body.
+    pub download_timeout: Duration,
+    /// Bytes per second the body must be received with, 0 for no limit.
+    pub min_download_speed: u64,
+    /// Time until a connection, including the TLS handshake, is established.
+    pub connect_timeout: Duration,
+    pub user_agent: HeaderValue,
+    pub proxy: ProxyOptions,
+    /// Headers sent to matching hosts.
+    pub header_rules: Vec<HeaderRule>,
+    /// Send a HEAD request before the GET, some origins reject HEAD requests.
+    pub head_request: bool,
+    /// Private networks images may be downloaded from.
+    pub allowed_networks: Vec<IpNet>,
+    /// Hosts images may be downloaded from, any host if empty.
+    pub allowed_hosts: Vec<HostPattern>,
+    pub denied_hosts: Vec<HostPattern>,
+}
+
+/// Phase of a download which timed out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutKind {
+    Connect,
+    FirstByte,
+    Total,
+    MinSpeed,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutKind::Connect => "connecting",
+            TimeoutKind::FirstByte => "waiting for the response",
+            TimeoutKind::Total => "downloading",
+            TimeoutKind::MinSpeed => "downloading below the minimum speed",
+        })
+    }
+}
+
+/// How the content type of downloaded images is checked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentTypeCheck {
+    /// `Content-Type` header must start with `image/`, the content is not looked at.
+    Header,
+    /// Content must start with the signature of a known image format.
+    Sniff,
+    /// Both, and the header must name the detected format.
+    Both,
 }
 
 impl DownloadService for Downloader {
-    fn download_image(&self, url: String) -> Box<dyn Future<Item = Bytes, Error = DownloadError>> {
-        let parsed_url = self.validate_url(&url);
-        if parsed_url.is_err() {
-            return Box::new(result(parsed_url.map(|_| Bytes::new())));
+    fn download_if_modified(
+        &self,
+        url: String,
+        validators: Validators,
+    ) -> Box<dyn Future<Item = DownloadedImage, Error = DownloadError>> {
+        let parsed_url = match self.validate_url(&url) {
+            Ok(parsed_url) => parsed_url,
+            Err(validation_err) => return Box::new(err(validation_err)),
         };
-        let validation_future = self
-            .validate_response_header(&url, self.opt.clone())
-            .map(|_| Bytes::new());
-        let get_future = self.get(&url);
-
-        Box::new(validation_future.and_then(|_| get_future))
+        let downloader = self.clone();
+        let attempts = loop_fn(1, move |attempt| {
+            let retrying = downloader.clone();
+            downloader
+                .attempt(parsed_url.clone(), validators.clone())
+                .then(move |result| retrying.retry(attempt, result))
+        });
+        // one deadline for all attempts and the delays between them.
+        Box::new(
+            Timeout::new(attempts, self.opt.download_timeout).map_err(move |err| {
+                err.into_inner().unwrap_or(DownloadError::Timeout {
+                    url,
+                    kind: TimeoutKind::Total,
+                })
+            }),
+        )
     }
 }
 
 impl Downloader {
-    pub fn new(opt: DownloadOptions, client: Client) -> Self {
+    pub fn new(opt: DownloadOptions, limiter: DownloadLimiter) -> Self {
+        let ip_filter = IpFilter::new(opt.allowed_networks.clone());
+        Downloader::with_ip_filter(opt, limiter, ip_filter)
+    }
+
+    /// The clients don't follow redirects, they are followed by the downloader
+    /// to check the destination of every hop.
+    pub fn with_ip_filter(
+        opt: DownloadOptions,
+        limiter: DownloadLimiter,
+        ip_filter: IpFilter,
+    ) -> Self {
+        let mut http = HttpConnector::new_with_resolver(ip_filter.clone());
+        http.enforce_http(false);
+        let tls = native_tls::TlsConnector::new().expect("failed to initialize tls");
+        let direct = hyper::Client::builder().build(ConnectTimeout {
+            inner: HttpsConnector::from((http, tls)),
+            timeout: opt.connect_timeout,
+        });
+        let proxied = opt.proxy.to_proxy().map(|proxy| {
+            Client::builder()
+                .connect_timeout(opt.connect_timeout)
+                .redirect(RedirectPolicy::none())
+                .proxy(proxy)
+                .build()
+                .expect("failed to create http client")
+        });
         Downloader {
-            client: client,
+            direct,
+            proxied,
+            limiter: limiter,
+            host_filter: HostFilter::new(opt.allowed_hosts.clone(), opt.denied_hosts.clone()),
+            ip_filter,
             opt: opt,
         }
     }
 
-    fn validate_response_header(
+    /// Downloads the image once a download from its host may start.
+    fn attempt(
         &self,
-        url: &str,
-        options: DownloadOptions,
-    ) -> impl Future<Item = (), Error = DownloadError> {
-        self.client
-            .head(url)
-            .send()
-            .map_err({
-                let url_owned = url.to_owned();
-                |err| {
-                    debug!("download image error: {}", err);
-                    DownloadError::FailedGetImage {
+        url: Url,
+        validators: Validators,
+    ) -> Box<dyn Future<Item = DownloadedImage, Error = DownloadError>> {
+        let downloader = self.clone();
+        let url_owned = url.to_string();
+        Box::new(
+            self.limiter
+                .acquire(url.host_str().unwrap_or(""))
+                .map_err(|_| DownloadError::FailedGetImage {
+                    url: url_owned,
+                    desc: "download cancelled while waiting".to_owned(),
+                })
+                .and_then(move |permit| {
+                    downloader.fetch(url, validators).then(move |result| {
+                        drop(permit);
+                        result
+                    })
+                }),
+        )
+    }
+
+    fn fetch(
+        &self,
+        url: Url,
+        validators: Validators,
+    ) -> Box<dyn Future<Item = DownloadedImage, Error = DownloadError>> {
+        if !self.opt.head_request {
+            return Box::new(self.get(url, validators));
+        }
+        let downloader = self.clone();
+        Box::new(
+            self.validate_response_header(url.clone(), validators.clone())
+                .and_then(move |not_modified| match not_modified {
+                    Some(image) => Either::A(ok(image)),
+                    None => Either::B(downloader.get(url, validators)),
+                }),
+        )
+    }
+
+    /// Waits and tries again after transient failures, as long as the retry policy allows.
+    fn retry(
+        &self,
+        attempt: u32,
+        result: Result<DownloadedImage, DownloadError>,
+    ) -> Box<dyn Future<Item = Loop<DownloadedImage, u32>, Error = DownloadError>> {
+        let error = match result {
+            Ok(image) => return Box::new(ok(Loop::Break(image))),
+            Err(error) => error,
+        };
+        // `Some` for transient failures, holding the `Retry-After` of the response if any.
+        let retry_after = match &error {
+            DownloadError::FailedGetImage { .. }
+            | DownloadError::FailedParsePayload { .. }
+            | DownloadError::Timeout { .. } => Some(None),
+            DownloadError::StatusCodeNotOK {
+                code, retry_after, ..
+            } if matches!(code.parse(), Ok(code) if self.opt.retry.is_retryable_status(code)) => {
+                Some(*retry_after)
+            }
+            _ => None,
+        };
+        let delay = retry_after.and_then(|retry_after| {
+            self.opt
+                .retry
+                .delay(attempt, retry_after, rand::random::<f64>())
+        });
+        match delay {
+            Some(delay) => {
+                warn!(
+                    "retrying download in {:?} after attempt {}: {}",
+                    delay, attempt, error
+                );
+                Box::new(
+                    Delay::new(Instant::now() + delay)
+                        .then(move |_| Ok(Loop::Continue(attempt + 1))),
+                )
+            }
+            None if attempt > 1 => Box::new(err(DownloadError::RetriesExhausted {
+                attempts: attempt,
+                error: Box::new(error),
+            })),
+            None => Box::new(err(error)),
+        }
+    }
+
+    /// Sends a request, and follows redirects the same way. Hosts are resolved when
+    /// connecting and only addresses allowed by the ip filter are connected to.
+    fn send(
+        &self,
+        method: Method,
+        url: Url,
+        redirects: usize,
+        validators: Validators,
+    ) -> Box<dyn Future<Item = (Url, Response), Error = DownloadError>> {
+        if let Err(filter_err) = self.ip_filter.check_literal(&url) {
+            return Box::new(err(destination_error(&url, &filter_err)));
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, self.opt.user_agent.clone());
+        headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+        // matched against every hop, so credentials stay with their hosts.
+        headers.extend(header_rules::headers_for(
+            &self.opt.header_rules,
+            url.host_str().unwrap_or(""),
+        ));
+        headers.extend(validators.conditional_headers());
+        let request = match self.proxied_client(&url) {
+            Some(client) => self.send_proxied(client, method.clone(), &url, headers),
+            None => self.send_direct(method.clone(), &url, headers),
+        };
+        let downloader = self.clone();
+        let url_owned = url.to_string();
+        Box::new(
+            Timeout::new(request, self.opt.first_byte_timeout)
+                .map_err(move |err| {
+                    err.into_inner().unwrap_or(DownloadError::Timeout {
                         url: url_owned,
-                        desc: format!("{}", err),
+                        kind: TimeoutKind::FirstByte,
+                    })
+                })
+                .and_then(move |res| match redirect_location(&res) {
+                    Some(location) => {
+                        downloader.follow_redirect(method, url, &location, redirects, validators)
                     }
+                    None => Box::new(ok((url, res))),
+                }),
+        )
+    }
+
+    /// Client sending requests for `url` through the proxy, if the url is proxied.
+    fn proxied_client(&self, url: &Url) -> Option<&Client> {
+        self.opt.proxy.proxy_for(url).and(self.proxied.as_ref())
+    }
+
+    fn send_direct(
+        &self,
+        method: Method,
+        url: &Url,
+        headers: HeaderMap,
+    ) -> Box<dyn Future<Item = Response, Error = DownloadError>> {
+        let mut request = hyper::Request::new(hyper::Body::empty());
+        *request.method_mut() = method;
+        *request.uri_mut() = match url.as_str().parse() {
+            Ok(uri) => uri,
+            Err(parse_err) => {
+                return Box::new(err(DownloadError::UrsParseError {
+                    url: url.to_string(),
+                    desc: format!("{}", parse_err),
+                }))
+            }
+        };
+        *request.headers_mut() = headers;
+        let url = url.clone();
+        Box::new(
+            self.direct
+                .request(request)
+                .map(|res| {
+                    Response::from(res.map(|body| {
+                        let body: Box<dyn Stream<Item = _, Error = _> + Send> = Box::new(body);
+                        Body::from(body)
+                    }))
+                })
+                .map_err(move |err| request_error(&url, &err)),
+        )
+    }
+
+    /// The proxy resolves the host again, the addresses it resolves to locally are
+    /// checked before, so a proxy doesn't open the way to private networks.
+    fn send_proxied(
+        &self,
+        client: &Client,
+        method: Method,
+        url: &Url,
+        headers: HeaderMap,
+    ) -> Box<dyn Future<Item = Response, Error = DownloadError>> {
+        let ip_filter = self.ip_filter.clone();
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => Some(domain.to_owned()),
+            _ => None,
+        };
+        let request = client.request(method, url.clone()).headers(headers);
+        let url = url.clone();
+        Box::new(
+            web::block(move || match host {
+                Some(host) => ip_filter.resolve(&host).map(|_| ()),
+                None => Ok(()),
+            })
+            .map_err({
+                let url = url.clone();
+                move |err| match err {
+                    error::BlockingError::Error(filter_err) => destination_error(&url, &filter_err),
+                    error::BlockingError::Canceled => DownloadError::FailedGetImage {
+                        url: url.to_string(),
+                        desc: "destination check cancelled".to_owned(),
+                    },
                 }
             })
-            .and_then(move |res| {
-                let status = res.status();
-                let url = res.url().as_str().to_owned();
-                if status != actix_web::http::StatusCode::OK {
-                    return Err(DownloadError::StatusCodeNotOK {
+            .and_then(move |_| request.send().map_err(move |err| request_error(&url, &err))),
+        )
+    }
+
+    fn follow_redirect(
+        &self,
+        method: Method,
+        url: Url,
+        location: &str,
+        redirects: usize,
+        validators: Validators,
+    ) -> Box<dyn Future<Item = (Url, Response), Error = DownloadError>> {
+        if redirects >= self.opt.max_redirects {
+            warn!("too many redirects: {}", url);
+            return Box::new(err(DownloadError::TooManyRedirects {
+                url: url.to_string(),
+            }));
+        }
+        match self.redirect_target(&url, location) {
+            Ok(next) => {
+                info!("following redirect from {} to {}", url, next);
+                self.send(method, next, redirects + 1, validators)
+            }
+            Err(redirect_err) => Box::new(err(redirect_err)),
+        }
+    }
+
+    /// Url a redirect leads to, if it may be followed.
+    fn redirect_target(&self, url: &Url, location: &str) -> Result<Url, DownloadError> {
+        let next = url
+            .join(location)
+            .map_err(|err| DownloadError::UrsParseError {
+                url: location.to_owned(),
+                desc: format!("{}", err),
+            })?;
+        if url.scheme() == "https" && next.scheme() == "http" && !self.opt.allow_https_downgrade {
+            warn!("blocked redirect from {} to {}", url, next);
+            return Err(DownloadError::InsecureRedirect {
+                url: url.to_string(),
+                location: next.to_string(),
+            });
+        }
+        self.validate_url(next.as_str())
+    }
+
+    /// HEAD pre-flight, the checks are done again on the GET response.
+    /// Resolves to the image if it was not modified, there is nothing to get then.
+    fn validate_response_header(
+        &self,
+        url: Url,
+        validators: Validators,
+    ) -> impl Future<Item = Option<DownloadedImage>, Error = DownloadError> {
+        let downloader = self.clone();
+        let requested_url = url.to_string();
+        self.send(Method::HEAD, url, 0, validators.clone())
+            .and_then(move |(final_url, res)| {
+                match not_modified(&final_url, &res, &requested_url, &validators) {
+                    Some(image) => Ok(Some(image)),
+                    None => downloader.check_response(&final_url, &res).map(|_| None),
+                }
+            })
+    }
+
+    /// Checks status, content length and content type of a response before its body is read.
+    fn check_response(&self, url: &Url, res: &Response) -> Result<(), DownloadError> {
+        let status = res.status();
+        let url = url.to_string();
+        if status != actix_web::http::StatusCode::OK {
+            return Err(DownloadError::StatusCodeNotOK {
+                url: url,
+                code: status.as_str().to_owned(),
+                retry_after: res
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|header| header.to_str().ok())
+                    .and_then(|header| retry::parse_retry_after(header, SystemTime::now())),
+            });
+        };
+        // without content length the limit is enforced while the body is streamed.
+        if let Some(max_content_length) = self.opt.max_content_length {
+            if let Some(actual_content_length) = res.content_length() {
+                if actual_content_length > max_content_length {
+                    return Err(DownloadError::ContentLenghtError {
                         url: url,
-                        code: status.as_str().to_owned(),
+                        actual_content_length: actual_content_length,
+                        max_content_length: max_content_length,
                     });
-                };
-                if let Some(max_content_length) = options.max_content_length {
-                    if let Some(actual_content_length) = res.content_length() {
-                        if actual_content_length > max_content_length {
-                            return Err(DownloadError::ContentLenghtError {
-                                url: url,
-                                actual_content_length: actual_content_length,
-                                max_content_length: max_content_length,
-                            });
-                        }
-                    } else {
-                        // ToDo: what if content length is not set in response..
-                        // If payload is too big, Client should timeout.
-                    }
-                };
-                if options.check_mime_type {
-                    let mut invalid_content_type = true;
-                    let mut content_type = "";
-                    if let Some(header) = res.headers().get(reqwest::header::CONTENT_TYPE) {
-                        if let Ok(header_str) = header.to_str() {
-                            content_type = header_str;
-                            if header_str.starts_with(MIME_PREFIX) {
-                                invalid_content_type = false;
-                            }
-                        }
-                    }
-                    if invalid_content_type {
-                        return Err(DownloadError::InvalidContentType {
-                            url: url,
-                            content_type: content_type.to_owned(),
-                            content_type_prefix: MIME_PREFIX.to_owned(),
-                        });
+                }
+            }
+        };
+        if self.opt.content_type_check != ContentTypeCheck::Sniff {
+            let mut invalid_content_type = true;
+            let mut content_type = "";
+            if let Some(header) = res.headers().get(reqwest::header::CONTENT_TYPE) {
+                if let Ok(header_str) = header.to_str() {
+                    content_type = header_str;
+                    if header_str.starts_with(MIME_PREFIX) {
+                        invalid_content_type = false;
                     }
                 }
-                return Ok(());
-            })
+            }
+            if invalid_content_type {
+                return Err(DownloadError::InvalidContentType {
+                    url: url,
+                    content_type: content_type.to_owned(),
+                    content_type_prefix: MIME_PREFIX.to_owned(),
+                });
+            }
+            // the content is checked against the allowed formats in the other modes.
+            if self.opt.content_type_check == ContentTypeCheck::Header
+                && !self.opt.allowed_input_formats.is_empty()
+            {
+                let allowed = InputFormat::from_mime_type(content_type)
+                    .is_some_and(|format| self.opt.allowed_input_formats.contains(&format));
+                if !allowed {
+                    return Err(DownloadError::InputFormatNotAllowed {
+                        url: url,
+                        format: content_type.to_owned(),
+                    });
+                }
+            }
+        }
+        return Ok(());
     }
 
-    fn get(&self, url: &str) -> impl Future<Item = Bytes, Error = DownloadError> {
-        self.client
-            .get(url)
-            .send()
-            .map_err({
-                let url_owned = url.to_owned();
-                |err| {
-                    debug!("download image error: {}", err);
-                    DownloadError::FailedGetImage {
-                        url: url_owned,
-                        desc: format!("{}", err),
-                    }
+    /// Checks the response headers, then streams the body, aborting as soon as it
+    /// exceeds `max_content_length`, whatever the headers say, or its first bytes
+    /// are not an accepted image.
+    fn get(
+        &self,
+        url: Url,
+        validators: Validators,
+    ) -> impl Future<Item = DownloadedImage, Error = DownloadError> {
+        let downloader = self.clone();
+        let max_content_length = self.opt.max_content_length;
+        let min_download_speed = self.opt.min_download_speed;
+        let requested_url = url.to_string();
+        self.send(Method::GET, url, 0, validators.clone())
+            .and_then(move |(final_url, res)| {
+                if let Some(image) = not_modified(&final_url, &res, &requested_url, &validators) {
+                    return Either::A(ok(image));
                 }
-            })
-            .and_then({
-                let url_owned = url.to_owned();
-                |res| {
-                    ok(res.into_body().concat2().map_err(|err| {
+                if let Err(check_err) = downloader.check_response(&final_url, &res) {
+                    return Either::A(err(check_err));
+                }
+                let url_owned = final_url.to_string();
+                let response_validators = Validators::from_response(&res);
+                let content_type = res
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|header| header.to_str().ok())
+                    .map(|header| header.to_owned());
+                let body = res.into_body().map_err({
+                    let url_owned = url_owned.clone();
+                    move |err| {
                         debug!("read image payload error: {}", err);
                         DownloadError::FailedParsePayload {
-                            url: url_owned,
+                            url: url_owned.clone(),
                             desc: format!("{}", err),
                         }
-                    }))
-                }
+                    }
+                });
+                let body = with_min_speed(body, &url_owned, min_download_speed)
+                    .fold((Vec::new(), false), {
+                        let downloader = downloader.clone();
+                        let url_owned = url_owned.clone();
+                        let content_type = content_type.clone();
+                        move |(mut payload, mut checked), chunk| {
+                            payload.extend_from_slice(&chunk);
+                            if !checked && payload.len() >= sniff::SIGNATURE_LEN {
+                                downloader.check_content(
+                                    &url_owned,
+                                    content_type.as_deref(),
+                                    &payload,
+                                )?;
+                                checked = true;
+                            }
+                            match max_content_length {
+                                Some(max_content_length)
+                                    if payload.len() as u64 > max_content_length =>
+                                {
+                                    debug!(
+                                        "image payload exceeds {} bytes: {}",
+                                        max_content_length, url_owned
+                                    );
+                                    Err(DownloadError::ContentLenghtError {
+                                        url: url_owned.clone(),
+                                        actual_content_length: payload.len() as u64,
+                                        max_content_length,
+                                    })
+                                }
+                                _ => Ok((payload, checked)),
+                            }
+                        }
+                    })
+                    .and_then(move |(payload, checked)| {
+                        // bodies shorter than a signature are checked once complete.
+                        if !checked {
+                            downloader.check_content(
+                                &url_owned,
+                                content_type.as_deref(),
+                                &payload,
+                            )?;
+                        }
+                        Ok(DownloadedImage {
+                            bytes: Bytes::from(payload),
+                            final_url: Some(url_owned).filter(|url| *url != requested_url),
+                            validators: response_validators,
+                            not_modified: false,
+                        })
+                    });
+                Either::B(body)
             })
-            .and_then(|b| b)
-            .map(|b| Bytes::from(b.as_ref()))
+    }
+
+    /// Unless only the header is checked, checks the format detected from the first bytes
+    /// of the body against the allowed input formats and, in `Both` mode, the `Content-Type` header.
+    fn check_content(
+        &self,
+        url: &str,
+        content_type: Option<&str>,
+        payload: &[u8],
+    ) -> Result<(), DownloadError> {
+        if self.opt.content_type_check == ContentTypeCheck::Header {
+            return Ok(());
+        }
+        let format = sniff::sniff(payload).ok_or_else(|| DownloadError::UnrecognizedImage {
+            url: url.to_owned(),
+        })?;
+        if !self.opt.allowed_input_formats.is_empty()
+            && !self.opt.allowed_input_formats.contains(&format)
+        {
+            return Err(DownloadError::InputFormatNotAllowed {
+                url: url.to_owned(),
+                format: format.name().to_owned(),
+            });
+        }
+        if self.opt.content_type_check == ContentTypeCheck::Both {
+            let content_type = content_type.unwrap_or("");
+            if InputFormat::from_mime_type(content_type) != Some(format) {
+                return Err(DownloadError::ContentTypeMismatch {
+                    url: url.to_owned(),
+                    content_type: content_type.to_owned(),
+                    format: format.name().to_owned(),
+                });
+            }
+        }
+        Ok(())
     }
 
     fn validate_url(&self, url: &str) -> Result<Url, DownloadError> {
@@ -156,25 +709,261 @@ impl Downloader {
                     desc: "incorrect scheme or host".to_owned(),
                 });
             })
+            .and_then(|u| {
+                let host = u.host_str().unwrap_or("");
+                if !self.host_filter.is_allowed(host) {
+                    debug!("image url host is not allowed: {}", host);
+                    return Err(DownloadError::HostNotAllowed {
+                        url: url.to_owned(),
+                        host: host.to_owned(),
+                    });
+                }
+                Ok(u)
+            })
+    }
+}
+
+/// The image of a `304 Not Modified` response to a conditional request. The validators
+/// of the response, if any, replace the ones the image was requested with.
+fn not_modified(
+    url: &Url,
+    res: &Response,
+    requested_url: &str,
+    validators: &Validators,
+) -> Option<DownloadedImage> {
+    if res.status() != StatusCode::NOT_MODIFIED || validators.is_empty() {
+        return None;
+    }
+    let url = url.as_str();
+    debug!("image not modified: {}", url);
+    let response_validators = Validators::from_response(res);
+    Some(DownloadedImage {
+        bytes: Bytes::new(),
+        final_url: Some(url.to_owned()).filter(|url| url != requested_url),
+        validators: if response_validators.is_empty() {
+            validators.clone()
+        } else {
+            response_validators
+        },
+        not_modified: true,
+    })
+}
+
+/// Body stream failing with a `MinSpeed` timeout as soon as less than `min_speed` bytes
+/// per second arrived since the headers, after `SPEED_CHECK_GRACE`. A timer runs between
+/// chunks, so a body which stalls fails too.
+struct MinSpeed<S> {
+    inner: S,
+    url: String,
+    min_speed: u64,
+    started: Instant,
+    received: u64,
+    /// Time the next chunk must arrive by.
+    deadline: Delay,
+}
+
+impl<S> MinSpeed<S> {
+    fn next_deadline(&self) -> Instant {
+        let expected = Duration::from_secs_f64(self.received as f64 / self.min_speed as f64);
+        self.started + expected.max(SPEED_CHECK_GRACE)
+    }
+
+    fn timeout(&self) -> DownloadError {
+        debug!("image payload too slow: {}", self.url);
+        DownloadError::Timeout {
+            url: self.url.clone(),
+            kind: TimeoutKind::MinSpeed,
+        }
+    }
+}
+
+impl<S> Stream for MinSpeed<S>
+where
+    S: Stream<Error = DownloadError>,
+    S::Item: AsRef<[u8]>,
+{
+    type Item = S::Item;
+    type Error = DownloadError;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, DownloadError> {
+        match self.inner.poll()? {
+            Async::Ready(Some(chunk)) => {
+                if Instant::now() > self.deadline.deadline() {
+                    return Err(self.timeout());
+                }
+                self.received += chunk.as_ref().len() as u64;
+                let deadline = self.next_deadline();
+                self.deadline.reset(deadline);
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => match self.deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Err(self.timeout()),
+            },
+        }
     }
 }
 
-#[derive(Fail, Debug)]
+/// Enforces `min_speed` bytes per second on the body, if it is not 0.
+fn with_min_speed<S>(body: S, url: &str, min_speed: u64) -> Either<MinSpeed<S>, S> {
+    if min_speed == 0 {
+        return Either::B(body);
+    }
+    let started = Instant::now();
+    Either::A(MinSpeed {
+        inner: body,
+        url: url.to_owned(),
+        min_speed,
+        started,
+        received: 0,
+        deadline: Delay::new(started + SPEED_CHECK_GRACE),
+    })
+}
+
+/// Error of a request, telling apart denied destinations and connect timeouts by its causes.
+fn request_error(url: &Url, request_err: &(dyn std::error::Error + 'static)) -> DownloadError {
+    let mut cause = Some(request_err);
+    while let Some(err) = cause {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::TimedOut {
+                return DownloadError::Timeout {
+                    url: url.to_string(),
+                    kind: TimeoutKind::Connect,
+                };
+            }
+            let filter_err = io_err
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<Compat<IpFilterError>>());
+            if let Some(filter_err) = filter_err {
+                return destination_error(url, filter_err.get_ref());
+            }
+        }
+        cause = err.source();
+    }
+    debug!("download image error: {}", request_err);
+    DownloadError::FailedGetImage {
+        url: url.to_string(),
+        desc: format!("{}", request_err),
+    }
+}
+
+fn destination_error(url: &Url, filter_err: &IpFilterError) -> DownloadError {
+    match filter_err {
+        IpFilterError::NotAllowed(address) => {
+            warn!("blocked request to {} ({})", url, address);
+            DownloadError::DestinationNotAllowed {
+                url: url.to_string(),
+                address: address.to_string(),
+            }
+        }
+        IpFilterError::ResolveFailed(_) => DownloadError::FailedGetImage {
+            url: url.to_string(),
+            desc: format!("{}", filter_err),
+        },
+    }
+}
+
+/// Connector failing with `TimedOut` if connecting takes longer than `timeout`.
+#[derive(Clone)]
+struct ConnectTimeout<C> {
+    inner: C,
+    timeout: Duration,
+}
+
+impl<C> Connect for ConnectTimeout<C>
+where
+    C: Connect<Error = io::Error>,
+    C::Future: 'static,
+{
+    type Transport = C::Transport;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (C::Transport, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        Box::new(
+            Timeout::new(self.inner.connect(dst), self.timeout).map_err(|err| {
+                err.into_inner()
+                    .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+            }),
+        )
+    }
+}
+
+/// Target of a redirect response.
+fn redirect_location(res: &Response) -> Option<String> {
+    match res.status().as_u16() {
+        301 | 302 | 303 | 307 | 308 => res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(|location| location.to_owned()),
+        _ => None,
+    }
+}
+
+impl FromStr for ContentTypeCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "header" => Ok(ContentTypeCheck::Header),
+            "sniff" => Ok(ContentTypeCheck::Sniff),
+            "both" => Ok(ContentTypeCheck::Both),
+            _ => Err(format!("unknown content type check '{}'", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentTypeCheck {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Fail, Debug, Clone)]
 pub enum DownloadError {
     #[fail(display = "Failed to parse url '{}' error: {}", url, desc)]
     UrsParseError { url: String, desc: String },
     #[fail(display = "Failed to get image (image url: '{}') error: {}", url, desc)]
     FailedGetImage { url: String, desc: String },
+    #[fail(
+        display = "Image url '{}' resolves to address {} which is not allowed",
+        url, address
+    )]
+    DestinationNotAllowed { url: String, address: String },
+    #[fail(display = "Host '{}' of image url '{}' is not allowed", host, url)]
+    HostNotAllowed { url: String, host: String },
+    #[fail(display = "Gave up after {} attempts: {}", attempts, error)]
+    RetriesExhausted {
+        attempts: u32,
+        error: Box<DownloadError>,
+    },
+    #[fail(display = "Image url '{}' redirected too many times", url)]
+    TooManyRedirects { url: String },
+    #[fail(
+        display = "Redirect from https url '{}' to http url '{}' is not allowed",
+        url, location
+    )]
+    InsecureRedirect { url: String, location: String },
+    #[fail(display = "Timed out {} (image url: '{}')", kind, url)]
+    Timeout { url: String, kind: TimeoutKind },
     #[fail(
         display = "Get image (image url: '{}') returned status code != 200: {}",
         url, code
     )]
-    StatusCodeNotOK { url: String, code: String },
+    StatusCodeNotOK {
+        url: String,
+        code: String,
+        retry_after: Option<Duration>,
+    },
     #[fail(
         display = "Failed to parse image payload (image url: '{}') error: {}",
         url, desc
     )]
     FailedParsePayload { url: String, desc: String },
+    /// `actual_content_length` is the number of bytes read so far if the body was cut short.
     #[fail(
         display = "Response from url '{}' returned content_length {} that exceeds max allowed {}",
         url, actual_content_length, max_content_length
@@ -193,4 +982,85 @@ pub enum DownloadError {
         content_type: String,
         content_type_prefix: String,
     },
+    #[fail(display = "Content of url '{}' is not a recognized image format", url)]
+    UnrecognizedImage { url: String },
+    #[fail(display = "Image format '{}' of url '{}' is not allowed", format, url)]
+    InputFormatNotAllowed { url: String, format: String },
+    #[fail(
+        display = "Response from url '{}' returned content_type '{}' but content is '{}'",
+        url, content_type, format
+    )]
+    ContentTypeMismatch {
+        url: String,
+        content_type: String,
+        format: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downloader(allow_https_downgrade: bool) -> Downloader {
+        Downloader::new(
+            DownloadOptions {
+                max_content_length: None,
+                content_type_check: ContentTypeCheck::Both,
+                allowed_input_formats: vec![],
+                head_request: false,
+                retry: RetryPolicy {
+                    max_attempts: 1,
+                    base_delay: Duration::from_millis(0),
+                    max_delay: Duration::from_millis(0),
+                    jitter: 0.0,
+                    retryable_status_codes: vec![],
+                },
+                first_byte_timeout: Duration::from_secs(5),
+                download_timeout: Duration::from_secs(5),
+                min_download_speed: 0,
+                connect_timeout: Duration::from_secs(2),
+                user_agent: HeaderValue::from_static("thumbnail_creator/0.1"),
+                proxy: ProxyOptions::default(),
+                header_rules: vec![],
+                max_redirects: 10,
+                allow_https_downgrade,
+                allowed_networks: vec![],
+                allowed_hosts: vec![],
+                denied_hosts: vec!["internal.example.com".parse().unwrap()],
+            },
+            DownloadLimiter::new(0, 0),
+        )
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        let url = Url::parse("https://example.com/images/1.jpg").unwrap();
+        let target = |downloader: &Downloader, location: &str| {
+            downloader
+                .redirect_target(&url, location)
+                .map(|next| next.to_string())
+                .map_err(|err| format!("{}", err))
+        };
+        let strict = downloader(false);
+        assert_eq!(
+            target(&strict, "2.jpg"),
+            Ok("https://example.com/images/2.jpg".to_owned())
+        );
+        assert_eq!(
+            target(&strict, "//cdn.example.com/1.jpg"),
+            Ok("https://cdn.example.com/1.jpg".to_owned())
+        );
+        assert_eq!(
+            target(&strict, "http://example.com/1.jpg"),
+            Err("Redirect from https url 'https://example.com/images/1.jpg' to http url 'http://example.com/1.jpg' is not allowed".to_owned())
+        );
+        assert!(target(&strict, "https://internal.example.com/1.jpg").is_err());
+        assert!(target(&strict, "file:///etc/passwd").is_err());
+
+        let lenient = downloader(true);
+        assert_eq!(
+            target(&lenient, "http://example.com/1.jpg"),
+            Ok("http://example.com/1.jpg".to_owned())
+        );
+    }
 }
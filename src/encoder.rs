@@ -0,0 +1,425 @@
+use failure::Fail;
+use image::GenericImageView;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+
+/// File format of created thumbnails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+    Gif,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+/// Encoder settings of every output format, only the ones of the chosen format are used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncoderOptions {
+    /// 1 to 100.
+    pub jpeg_quality: u8,
+    pub jpeg_progressive: bool,
+    pub png_compression: PngCompression,
+    pub webp_lossless: bool,
+    /// 0 to 100, used by lossy WebP only.
+    pub webp_quality: u8,
+    /// 1 to 100.
+    pub avif_quality: u8,
+    /// 1 (slowest, smallest file) to 10 (fastest).
+    pub avif_speed: u8,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Gif => "gif",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        EncoderOptions {
+            jpeg_quality: 75,
+            jpeg_progressive: false,
+            png_compression: PngCompression::Default,
+            webp_lossless: false,
+            webp_quality: 75,
+            avif_quality: 60,
+            avif_speed: 8,
+        }
+    }
+}
+
+impl EncoderOptions {
+    /// Checks the settings of every format.
+    pub fn validate(&self) -> Result<(), String> {
+        [
+            OutputFormat::Jpeg,
+            OutputFormat::Png,
+            OutputFormat::Webp,
+            OutputFormat::Avif,
+            OutputFormat::Gif,
+        ]
+        .iter()
+        .try_for_each(|&format| self.validate_format(format))
+    }
+
+    /// Checks the settings `format` is encoded with.
+    pub fn validate_format(&self, format: OutputFormat) -> Result<(), String> {
+        match format {
+            OutputFormat::Jpeg if self.jpeg_quality < 1 || self.jpeg_quality > 100 => Err(format!(
+                "jpeg_quality {} is not between 1 and 100",
+                self.jpeg_quality
+            )),
+            OutputFormat::Webp if self.webp_quality > 100 => Err(format!(
+                "webp_quality {} is not between 0 and 100",
+                self.webp_quality
+            )),
+            OutputFormat::Avif if self.avif_quality < 1 || self.avif_quality > 100 => Err(format!(
+                "avif_quality {} is not between 1 and 100",
+                self.avif_quality
+            )),
+            OutputFormat::Avif if self.avif_speed < 1 || self.avif_speed > 10 => Err(format!(
+                "avif_speed {} is not between 1 and 10",
+                self.avif_speed
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Suffix distinguishing files encoded with settings other than `defaults`.
+    /// Only settings of `format` are compared.
+    pub fn variant(&self, format: OutputFormat, defaults: &EncoderOptions) -> Option<String> {
+        match format {
+            OutputFormat::Jpeg
+                if self.jpeg_quality != defaults.jpeg_quality
+                    || self.jpeg_progressive != defaults.jpeg_progressive =>
+            {
+                Some(format!(
+                    "q{}{}",
+                    self.jpeg_quality,
+                    if self.jpeg_progressive { "p" } else { "" }
+                ))
+            }
+            OutputFormat::Png if self.png_compression != defaults.png_compression => {
+                Some(self.png_compression.name().to_owned())
+            }
+            OutputFormat::Webp
+                if self.webp_lossless != defaults.webp_lossless
+                    || (!self.webp_lossless && self.webp_quality != defaults.webp_quality) =>
+            {
+                if self.webp_lossless {
+                    Some("lossless".to_owned())
+                } else {
+                    Some(format!("q{}", self.webp_quality))
+                }
+            }
+            OutputFormat::Avif
+                if self.avif_quality != defaults.avif_quality
+                    || self.avif_speed != defaults.avif_speed =>
+            {
+                Some(format!("q{}s{}", self.avif_quality, self.avif_speed))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl PngCompression {
+    pub fn name(self) -> &'static str {
+        match self {
+            PngCompression::Fast => "fast",
+            PngCompression::Default => "default",
+            PngCompression::Best => "best",
+        }
+    }
+}
+
+/// Encodes an image. Alpha channel is dropped for JPEG.
+pub fn encode(
+    img: &image::DynamicImage,
+    format: OutputFormat,
+    opt: &EncoderOptions,
+) -> Result<Vec<u8>, EncodeError> {
+    let (width, height) = img.dimensions();
+    let mut buf = Vec::new();
+    match format {
+        OutputFormat::Jpeg => {
+            if width > u32::from(u16::MAX) || height > u32::from(u16::MAX) {
+                return Err(EncodeError::TooLarge(width, height));
+            }
+            let mut encoder = jpeg_encoder::Encoder::new(&mut buf, opt.jpeg_quality);
+            encoder.set_progressive(opt.jpeg_progressive);
+            encoder
+                .encode(
+                    &img.to_rgb().into_raw(),
+                    width as u16,
+                    height as u16,
+                    jpeg_encoder::ColorType::Rgb,
+                )
+                .map_err(|err| EncodeError::Failed(format!("{}", err)))?;
+        }
+        OutputFormat::Png => {
+            use png::HasParameters;
+            let mut encoder = png::Encoder::new(&mut buf, width, height);
+            let compression = match opt.png_compression {
+                PngCompression::Fast => png::Compression::Fast,
+                PngCompression::Default => png::Compression::Default,
+                PngCompression::Best => png::Compression::Best,
+            };
+            let data = if has_alpha(img) {
+                encoder.set(png::ColorType::RGBA);
+                img.to_rgba().into_raw()
+            } else {
+                encoder.set(png::ColorType::RGB);
+                img.to_rgb().into_raw()
+            };
+            encoder.set(png::BitDepth::Eight).set(compression);
+            encoder
+                .write_header()
+                .and_then(|mut writer| writer.write_image_data(&data))
+                .map_err(|err| EncodeError::Failed(format!("{}", err)))?;
+        }
+        OutputFormat::Webp => {
+            let data;
+            let encoder = if has_alpha(img) {
+                data = img.to_rgba().into_raw();
+                webp::Encoder::from_rgba(&data, width, height)
+            } else {
+                data = img.to_rgb().into_raw();
+                webp::Encoder::from_rgb(&data, width, height)
+            };
+            let encoded = if opt.webp_lossless {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(f32::from(opt.webp_quality))
+            };
+            buf.extend_from_slice(&encoded);
+        }
+        OutputFormat::Avif => {
+            let encoder = ravif::Encoder::new()
+                .with_quality(f32::from(opt.avif_quality))
+                .with_speed(opt.avif_speed);
+            let rgba = img.to_rgba();
+            let pixels = rgba
+                .pixels()
+                .map(|p| ravif::RGBA8::new(p.data[0], p.data[1], p.data[2], p.data[3]))
+                .collect::<Vec<_>>();
+            let encoded = encoder
+                .encode_rgba(ravif::Img::new(
+                    &pixels[..],
+                    width as usize,
+                    height as usize,
+                ))
+                .map_err(|err| EncodeError::Failed(format!("{}", err)))?;
+            buf = encoded.avif_file;
+        }
+        OutputFormat::Gif => {
+            img.write_to(&mut buf, image::ImageOutputFormat::GIF)
+                .map_err(|err| EncodeError::Failed(format!("{}", err)))?;
+        }
+    }
+    Ok(buf)
+}
+
+fn has_alpha(img: &image::DynamicImage) -> bool {
+    matches!(
+        img.color(),
+        image::ColorType::GrayA(_) | image::ColorType::RGBA(_) | image::ColorType::BGRA(_)
+    )
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::Webp),
+            "avif" => Ok(OutputFormat::Avif),
+            "gif" => Ok(OutputFormat::Gif),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(format!("unknown png compression '{}'", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PngCompression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum EncodeError {
+    #[fail(display = "Image {}x{} is too large for the output format", _0, _1)]
+    TooLarge(u32, u32),
+    #[fail(display = "Encoding failed: {}", _0)]
+    Failed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> EncoderOptions {
+        EncoderOptions {
+            avif_speed: 10,
+            ..EncoderOptions::default()
+        }
+    }
+
+    fn source() -> image::DynamicImage {
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+        }))
+    }
+
+    #[test]
+    fn test_encode_signatures() {
+        let cases: Vec<(OutputFormat, &[u8], usize)> = vec![
+            (OutputFormat::Jpeg, b"\xff\xd8\xff", 0),
+            (OutputFormat::Png, b"\x89PNG", 0),
+            (OutputFormat::Webp, b"WEBP", 8),
+            (OutputFormat::Avif, b"ftypavif", 4),
+            (OutputFormat::Gif, b"GIF8", 0),
+        ];
+        for (format, signature, offset) in cases {
+            let bytes = encode(&source(), format, &options()).unwrap();
+            assert_eq!(
+                &bytes[offset..offset + signature.len()],
+                signature,
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        for &format in &[OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Gif] {
+            let bytes = encode(&source(), format, &options()).unwrap();
+            let img = image::load_from_memory(&bytes).unwrap();
+            assert_eq!(img.dimensions(), (64, 48), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_has_alpha() {
+        let transparent = image::DynamicImage::ImageBgra8(image::ImageBuffer::from_pixel(
+            4,
+            4,
+            image::Bgra([0, 0, 255, 0]),
+        ));
+        assert!(has_alpha(&transparent));
+        assert!(has_alpha(&image::DynamicImage::ImageRgba8(
+            transparent.to_rgba()
+        )));
+        assert!(!has_alpha(&source()));
+        assert!(!has_alpha(&image::DynamicImage::ImageBgr8(
+            image::ImageBuffer::new(4, 4)
+        )));
+        // the alpha channel of BGRA images is kept.
+        let bytes = encode(&transparent, OutputFormat::Png, &options()).unwrap();
+        let img = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(img.color(), image::ColorType::RGBA(8));
+        assert_eq!(img.to_rgba().get_pixel(0, 0).data, [255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_jpeg_options() {
+        let baseline = encode(&source(), OutputFormat::Jpeg, &options()).unwrap();
+        let mut opt = options();
+        opt.jpeg_progressive = true;
+        let progressive = encode(&source(), OutputFormat::Jpeg, &opt).unwrap();
+        // SOF2 marker starts a progressive frame.
+        assert!(progressive.windows(2).any(|w| w == [0xff, 0xc2]));
+        assert!(!baseline.windows(2).any(|w| w == [0xff, 0xc2]));
+        opt.jpeg_quality = 10;
+        opt.jpeg_progressive = false;
+        let low_quality = encode(&source(), OutputFormat::Jpeg, &opt).unwrap();
+        assert!(low_quality.len() < baseline.len());
+    }
+
+    #[test]
+    fn test_validate_format() {
+        let mut opt = options();
+        opt.jpeg_quality = 0;
+        opt.avif_speed = 11;
+        assert_eq!(opt.validate_format(OutputFormat::Webp), Ok(()));
+        assert_eq!(opt.validate_format(OutputFormat::Png), Ok(()));
+        assert_eq!(
+            opt.validate_format(OutputFormat::Jpeg),
+            Err("jpeg_quality 0 is not between 1 and 100".to_owned())
+        );
+        assert_eq!(
+            opt.validate_format(OutputFormat::Avif),
+            Err("avif_speed 11 is not between 1 and 10".to_owned())
+        );
+        assert!(opt.validate().is_err());
+        assert_eq!(options().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_variant() {
+        let defaults = options();
+        let mut opt = options();
+        assert_eq!(opt.variant(OutputFormat::Jpeg, &defaults), None);
+        opt.jpeg_quality = 60;
+        opt.webp_lossless = true;
+        assert_eq!(
+            opt.variant(OutputFormat::Jpeg, &defaults),
+            Some("q60".to_owned())
+        );
+        assert_eq!(
+            opt.variant(OutputFormat::Webp, &defaults),
+            Some("lossless".to_owned())
+        );
+        assert_eq!(opt.variant(OutputFormat::Png, &defaults), None);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::encoder::OutputFormat;
+use crate::storage;
+use crate::thumbnail_handler::HandlerOptions;
+use actix_files::NamedFile;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{error, web, HttpRequest, HttpResponse, Responder};
+use std::cmp::Ordering;
+
+/// Serves stored thumbnails. An alternative in a format preferred by the `Accept` header
+/// is served instead of the requested file, if it was stored.
+pub fn serve<S: storage::StorageService + 'static>(
+    storage: web::Data<S>,
+    options: web::Data<HandlerOptions>,
+    req: HttpRequest,
+) -> Result<HttpResponse, error::Error> {
+    let handle = storage
+        .get_stored_handle(req.match_info().query("filename"))
+        .map_err(|_| error::ErrorNotFound("Thumbnail not found"))?;
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let alternative = accepted_formats(accept, &options.negotiated_formats)
+        .into_iter()
+        .map(|format| (format, storage.get_alternative_handle(&handle, format)))
+        .find(|(_, alternative)| alternative.exists());
+
+    let mut response = match alternative {
+        Some((format, alternative)) => {
+            let mut response = NamedFile::open(storage.get_base_path().join(alternative.path()))?
+                .respond_to(&req)?;
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.mime_type()),
+            );
+            response
+        }
+        None => NamedFile::open(storage.get_base_path().join(handle.path()))?.respond_to(&req)?,
+    };
+    if !options.negotiated_formats.is_empty() {
+        // caches must not serve one client's format to another.
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    Ok(response)
+}
+
+/// Formats of `formats` explicitly listed in the `Accept` header, most preferred first.
+/// Wildcards are ignored, so clients get alternatives only if they ask for them by name.
+fn accepted_formats(accept: &str, formats: &[OutputFormat]) -> Vec<OutputFormat> {
+    let mut accepted = formats
+        .iter()
+        .filter_map(|&format| {
+            media_type_quality(accept, format.mime_type())
+                .filter(|&quality| quality > 0.0)
+                .map(|quality| (format, quality))
+        })
+        .collect::<Vec<_>>();
+    // stable sort keeps the configured order of equally weighted formats.
+    accepted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    accepted.into_iter().map(|(format, _)| format).collect()
+}
+
+fn media_type_quality(accept: &str, mime_type: &str) -> Option<f32> {
+    accept.split(',').find_map(|media_range| {
+        let mut params = media_range.split(';');
+        if !params.next()?.trim().eq_ignore_ascii_case(mime_type) {
+            return None;
+        }
+        let quality = params
+            .filter_map(|param| {
+                let mut pair = param.splitn(2, '=');
+                match (pair.next()?.trim(), pair.next()) {
+                    ("q", Some(value)) | ("Q", Some(value)) => value.trim().parse().ok(),
+                    _ => None,
+                }
+            })
+            .next();
+        Some(quality.unwrap_or(1.0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [OutputFormat; 2] = [OutputFormat::Avif, OutputFormat::Webp];
+
+    #[test]
+    fn test_accepted_formats() {
+        let cases = vec![
+            ("", vec![]),
+            ("*/*", vec![]),
+            ("image/*,*/*;q=0.8", vec![]),
+            ("image/webp,*/*", vec![OutputFormat::Webp]),
+            (
+                "image/avif,image/webp,image/apng,image/*,*/*;q=0.8",
+                vec![OutputFormat::Avif, OutputFormat::Webp],
+            ),
+            (
+                "image/webp, image/avif;q=0.5",
+                vec![OutputFormat::Webp, OutputFormat::Avif],
+            ),
+            ("image/AVIF ; q=0, image/webp", vec![OutputFormat::Webp]),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(accepted_formats(accept, &FORMATS), expected, "{}", accept);
+        }
+    }
+
+    #[test]
+    fn test_no_negotiated_formats() {
+        assert!(accepted_formats("image/avif,image/webp", &[]).is_empty());
+    }
+}
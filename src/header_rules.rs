@@ -0,0 +1,177 @@
+use crate::host_filter::HostPattern;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Headers sent with requests to matching hosts, as configured. Header values, the
+/// bearer token and the basic auth password may be read from an environment variable
+/// with `env:NAME` or from a file with `file:/path`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeaderRuleConfig {
+    /// Host patterns as in `allowed_hosts`.
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Headers sent with requests to the hosts matching any of `hosts`.
+#[derive(Clone)]
+pub struct HeaderRule {
+    hosts: Vec<HostPattern>,
+    headers: HeaderMap,
+}
+
+impl HeaderRule {
+    /// Resolves secrets and validates the header names and values.
+    pub fn from_config(config: &HeaderRuleConfig) -> Result<Self, String> {
+        if config.hosts.is_empty() {
+            return Err("header rule without hosts".to_owned());
+        }
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|host| host.parse())
+            .collect::<Result<_, _>>()?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = name
+                .parse::<HeaderName>()
+                .map_err(|_| format!("invalid header name '{}'", name))?;
+            headers.insert(name, header_value(&resolve_secret(value)?)?);
+        }
+        if let Some(token) = &config.bearer_token {
+            let token = resolve_secret(token)?;
+            headers.insert(AUTHORIZATION, header_value(&format!("Bearer {}", token))?);
+        }
+        if let Some(auth) = &config.basic_auth {
+            let credentials = format!("{}:{}", auth.username, resolve_secret(&auth.password)?);
+            let value = format!("Basic {}", base64::encode(&credentials));
+            headers.insert(AUTHORIZATION, header_value(&value)?);
+        }
+        Ok(HeaderRule { hosts, headers })
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.hosts.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+impl fmt::Debug for HeaderRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HeaderRule")
+            .field("hosts", &self.hosts)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Headers of all rules matching `host`, later rules override earlier ones.
+pub fn headers_for(rules: &[HeaderRule], host: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for rule in rules.iter().filter(|rule| rule.matches(host)) {
+        for (name, value) in &rule.headers {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    headers
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, String> {
+    let mut value = HeaderValue::from_str(value).map_err(|_| "invalid header value".to_owned())?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Value of `env:NAME` and `file:/path` references, other values as they are.
+pub fn resolve_secret(value: &str) -> Result<String, String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).map_err(|_| format!("environment variable '{}' is not set", name))
+    } else if let Some(path) = value.strip_prefix("file:") {
+        std::fs::read_to_string(path)
+            .map(|secret| secret.trim_end_matches(&['\r', '\n'][..]).to_owned())
+            .map_err(|err| format!("failed to read '{}': {}", path, err))
+    } else {
+        Ok(value.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(hosts: &[&str], headers: &[(&str, &str)]) -> HeaderRuleConfig {
+        HeaderRuleConfig {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            bearer_token: None,
+            basic_auth: None,
+        }
+    }
+
+    #[test]
+    fn test_headers_for() {
+        let rules = [
+            rule(&["*.example.com"], &[("Referer", "https://example.com/")]),
+            rule(
+                &["cdn.example.com"],
+                &[("Referer", "https://cdn.example.com/")],
+            ),
+        ]
+        .iter()
+        .map(|config| HeaderRule::from_config(config).unwrap())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            headers_for(&rules, "images.example.com")["referer"],
+            "https://example.com/"
+        );
+        assert_eq!(
+            headers_for(&rules, "CDN.example.com")["referer"],
+            "https://cdn.example.com/"
+        );
+        assert!(headers_for(&rules, "example.org").is_empty());
+        assert!(headers_for(&rules, "example.com.evil.org").is_empty());
+    }
+
+    #[test]
+    fn test_credentials() {
+        std::env::set_var("HEADER_RULES_TEST_TOKEN", "s3cret");
+        let path = std::env::temp_dir().join(format!("header_rules_{:x}", rand::random::<u64>()));
+        std::fs::write(&path, "pa55\n").unwrap();
+
+        let mut bearer = rule(&["api.example.com"], &[]);
+        bearer.bearer_token = Some("env:HEADER_RULES_TEST_TOKEN".to_owned());
+        let bearer = HeaderRule::from_config(&bearer).unwrap();
+        assert_eq!(bearer.headers[AUTHORIZATION], "Bearer s3cret");
+        assert!(!format!("{:?}", bearer).contains("s3cret"));
+
+        let mut basic = rule(&["images.example.com"], &[]);
+        basic.basic_auth = Some(BasicAuth {
+            username: "bot".to_owned(),
+            password: format!("file:{}", path.display()),
+        });
+        let basic = HeaderRule::from_config(&basic).unwrap();
+        // base64 of "bot:pa55"
+        assert_eq!(basic.headers[AUTHORIZATION], "Basic Ym90OnBhNTU=");
+        std::fs::remove_file(&path).unwrap();
+
+        let mut missing = rule(&["api.example.com"], &[]);
+        missing.bearer_token = Some("env:HEADER_RULES_TEST_MISSING".to_owned());
+        assert!(HeaderRule::from_config(&missing).is_err());
+        assert!(HeaderRule::from_config(&rule(&[], &[("Referer", "x")])).is_err());
+        assert!(HeaderRule::from_config(&rule(&["a.com"], &[("Bad Name", "x")])).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+/// Host name, or all subdomains of a domain when written as `*.example.com`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    Subdomains(String),
+}
+
+/// Allowlist and denylist of image url hosts. Denied hosts win, and with a non-empty
+/// allowlist only the hosts on it are allowed.
+#[derive(Debug, Clone, Default)]
+pub struct HostFilter {
+    allowed: Vec<HostPattern>,
+    denied: Vec<HostPattern>,
+}
+
+impl HostFilter {
+    pub fn new(allowed: Vec<HostPattern>, denied: Vec<HostPattern>) -> Self {
+        HostFilter { allowed, denied }
+    }
+
+    pub fn is_allowed(&self, host: &str) -> bool {
+        let host = normalize(host);
+        if self
+            .denied
+            .iter()
+            .any(|pattern| pattern.matches_normalized(&host))
+        {
+            return false;
+        }
+        self.allowed.is_empty()
+            || self
+                .allowed
+                .iter()
+                .any(|pattern| pattern.matches_normalized(&host))
+    }
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        self.matches_normalized(&normalize(host))
+    }
+
+    fn matches_normalized(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Subdomains(domain) => {
+                host.len() > domain.len() + 1
+                    && host.ends_with(domain.as_str())
+                    && host[..host.len() - domain.len()].ends_with('.')
+            }
+        }
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = normalize(s.trim());
+        let (domain, subdomains) = match pattern.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (pattern.as_str(), false),
+        };
+        if domain.is_empty() || domain.contains('*') || domain.contains('/') {
+            return Err(format!("invalid host pattern '{}'", s));
+        }
+        Ok(if subdomains {
+            HostPattern::Subdomains(domain.to_owned())
+        } else {
+            HostPattern::Exact(domain.to_owned())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<HostPattern> {
+        patterns.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_pattern_parse() {
+        assert_eq!(
+            "*.CDN.example.com.".parse(),
+            Ok(HostPattern::Subdomains("cdn.example.com".to_owned()))
+        );
+        assert_eq!(
+            "example.com".parse(),
+            Ok(HostPattern::Exact("example.com".to_owned()))
+        );
+        for invalid in &["", "*", "*.", "cdn.*.example.com", "example.com/images"] {
+            assert!(invalid.parse::<HostPattern>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let filter = HostFilter::new(
+            patterns(&["*.cdn.example.com", "images.example.org"]),
+            vec![],
+        );
+        assert!(filter.is_allowed("a.cdn.example.com"));
+        assert!(filter.is_allowed("a.b.CDN.example.com."));
+        assert!(filter.is_allowed("images.example.org"));
+        assert!(!filter.is_allowed("cdn.example.com"));
+        assert!(!filter.is_allowed("evilcdn.example.com"));
+        assert!(!filter.is_allowed("a.images.example.org"));
+        assert!(!filter.is_allowed("example.net"));
+    }
+
+    #[test]
+    fn test_denied_hosts() {
+        let filter = HostFilter::new(
+            patterns(&["*.example.com"]),
+            patterns(&["*.internal.example.com", "admin.example.com"]),
+        );
+        assert!(filter.is_allowed("cdn.example.com"));
+        assert!(!filter.is_allowed("admin.example.com"));
+        assert!(!filter.is_allowed("db.internal.example.com"));
+        let filter = HostFilter::new(vec![], patterns(&["example.net"]));
+        assert!(filter.is_allowed("example.com"));
+        assert!(!filter.is_allowed("example.net"));
+    }
+}
@@ -0,0 +1,268 @@
+use actix_web::{error, web};
+use failure::Fail;
+use futures::Future;
+use hyper::client::connect::dns::{Name, Resolve};
+use ipnet::IpNet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use url::{Host, Url};
+
+/// Ranges image urls may not point to: private, loopback, link-local, CGNAT, ULA,
+/// multicast and other special purpose networks.
+const DENIED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    // local-use NAT64 and Teredo tunnel to IPv4 addresses which are not decoded and checked.
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001::/32",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "fec0::/10",
+    "ff00::/8",
+];
+
+/// Checks the addresses a url host resolves to against denied networks. As the resolver
+/// of the http client, connections are only made to the addresses which were checked.
+#[derive(Debug, Clone)]
+pub struct IpFilter {
+    denied: Vec<IpNet>,
+    /// Networks allowed even if they are in a denied range.
+    allowed: Vec<IpNet>,
+    lookup: fn(&str) -> io::Result<Vec<IpAddr>>,
+}
+
+impl IpFilter {
+    pub fn new(allowed: Vec<IpNet>) -> Self {
+        IpFilter {
+            denied: DENIED_NETWORKS
+                .iter()
+                .map(|network| network.parse().expect("invalid denied network"))
+                .collect(),
+            allowed,
+            lookup: system_lookup,
+        }
+    }
+
+    /// Filter resolving hosts with `lookup` instead of the system resolver.
+    #[cfg(test)]
+    pub fn with_lookup(allowed: Vec<IpNet>, lookup: fn(&str) -> io::Result<Vec<IpAddr>>) -> Self {
+        IpFilter {
+            lookup,
+            ..IpFilter::new(allowed)
+        }
+    }
+
+    /// Checks url hosts which are addresses, host names are checked when they are resolved.
+    pub fn check_literal(&self, url: &Url) -> Result<(), IpFilterError> {
+        let ip = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => return Ok(()),
+        };
+        if self.is_allowed(ip) {
+            Ok(())
+        } else {
+            Err(IpFilterError::NotAllowed(ip))
+        }
+    }
+
+    /// Addresses of `host`, if all of them are allowed.
+    /// Blocking, as the host is resolved with the system resolver.
+    pub fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, IpFilterError> {
+        let addresses = match host.parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => (self.lookup)(host)
+                .map_err(|err| IpFilterError::ResolveFailed(format!("{}", err)))?,
+        };
+        if addresses.is_empty() {
+            return Err(IpFilterError::ResolveFailed(
+                "host has no addresses".to_owned(),
+            ));
+        }
+        match addresses.iter().find(|ip| !self.is_allowed(**ip)) {
+            Some(ip) => Err(IpFilterError::NotAllowed(*ip)),
+            None => Ok(addresses),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 addresses embedded in IPv6 ones are checked as IPv4 too,
+        // so `::ffff:127.0.0.1` is denied like `127.0.0.1`.
+        let candidates = match ip {
+            IpAddr::V6(ipv6) => match embedded_ipv4(ipv6) {
+                Some(ipv4) => vec![ip, IpAddr::V4(ipv4)],
+                None => vec![ip],
+            },
+            IpAddr::V4(_) => vec![ip],
+        };
+        if candidates
+            .iter()
+            .any(|ip| self.allowed.iter().any(|network| network.contains(ip)))
+        {
+            return true;
+        }
+        !candidates
+            .iter()
+            .any(|ip| self.denied.iter().any(|network| network.contains(ip)))
+    }
+}
+
+/// IPv4 address of IPv4-mapped (`::ffff:0:0/96`), IPv4-compatible (`::/96`),
+/// NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`) addresses.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let octets = ip.octets();
+    match segments {
+        [0, 0, 0, 0, 0, 0xffff, _, _]
+        | [0, 0, 0, 0, 0, 0, _, _]
+        | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        )),
+        [0x2002, _, _, _, _, _, _, _] => {
+            Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5]))
+        }
+        _ => None,
+    }
+}
+
+fn system_lookup(host: &str) -> io::Result<Vec<IpAddr>> {
+    Ok((host, 0)
+        .to_socket_addrs()?
+        .map(|address| address.ip())
+        .collect())
+}
+
+impl Resolve for IpFilter {
+    type Addrs = std::vec::IntoIter<IpAddr>;
+    type Future = Box<dyn Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+    /// Fails with an `IpFilterError` wrapped in the `io::Error` if an address is not allowed.
+    fn resolve(&self, name: Name) -> Self::Future {
+        let filter = self.clone();
+        Box::new(
+            web::block(move || filter.resolve(name.as_str()))
+                .map(|addresses| addresses.into_iter())
+                .map_err(|err| match err {
+                    error::BlockingError::Error(err) => io::Error::other(err.compat()),
+                    error::BlockingError::Canceled => io::Error::other("resolving cancelled"),
+                }),
+        )
+    }
+}
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum IpFilterError {
+    #[fail(display = "destination address {} is not allowed", _0)]
+    NotAllowed(IpAddr),
+    #[fail(display = "failed to resolve host: {}", _0)]
+    ResolveFailed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_denied_addresses() {
+        let filter = IpFilter::new(vec![]);
+        let denied = vec![
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+            "2002:c0a8:101::1",
+            "64:ff9b:1::a00:1",
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+        ];
+        for address in denied {
+            assert!(!filter.is_allowed(ip(address)), "{}", address);
+        }
+        let allowed = vec![
+            "8.8.8.8",
+            "172.32.0.1",
+            "2606:4700::1111",
+            "2001:4860:4860::8888",
+            "::ffff:8.8.8.8",
+        ];
+        for address in allowed {
+            assert!(filter.is_allowed(ip(address)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let filter = IpFilter::new(vec!["10.1.0.0/16".parse().unwrap()]);
+        assert!(filter.is_allowed(ip("10.1.2.3")));
+        assert!(filter.is_allowed(ip("::ffff:10.1.2.3")));
+        assert!(!filter.is_allowed(ip("10.2.0.1")));
+    }
+
+    #[test]
+    fn test_check_literal() {
+        let filter = IpFilter::new(vec![]);
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            filter.check_literal(&url("http://169.254.169.254/latest/meta-data")),
+            Err(IpFilterError::NotAllowed(ip("169.254.169.254")))
+        );
+        assert_eq!(
+            filter.check_literal(&url("http://[::ffff:7f00:1]:6379/")),
+            Err(IpFilterError::NotAllowed(ip("::ffff:7f00:1")))
+        );
+        assert_eq!(filter.check_literal(&url("http://8.8.8.8/")), Ok(()));
+        assert_eq!(filter.check_literal(&url("http://localhost:6379/")), Ok(()));
+        assert!(filter.resolve("localhost").is_err());
+    }
+
+    #[test]
+    fn test_rebinding() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+        // a public address first, then the metadata service.
+        fn rebinding(_: &str) -> io::Result<Vec<IpAddr>> {
+            match LOOKUPS.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(vec![ip("93.184.216.34")]),
+                _ => Ok(vec![ip("8.8.8.8"), ip("169.254.169.254")]),
+            }
+        }
+        let filter = IpFilter::with_lookup(vec![], rebinding);
+        assert_eq!(
+            filter.resolve("rebinding.example.com"),
+            Ok(vec![ip("93.184.216.34")])
+        );
+        assert_eq!(
+            filter.resolve("rebinding.example.com"),
+            Err(IpFilterError::NotAllowed(ip("169.254.169.254")))
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use futures::future::{ok, Future};
+use futures::sync::oneshot;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Limits downloads in flight, in total and per host, across all workers.
+/// Downloads over a limit wait in line for a permit.
+#[derive(Debug, Clone)]
+pub struct DownloadLimiter {
+    /// 0 for no limit.
+    max_in_flight: usize,
+    /// 0 for no limit.
+    max_per_host: usize,
+    state: Arc<Mutex<LimiterState>>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    in_flight: usize,
+    per_host: HashMap<String, usize>,
+    waiting: VecDeque<(String, oneshot::Sender<DownloadPermit>)>,
+}
+
+/// Allows one download from `host`, until dropped.
+#[derive(Debug)]
+pub struct DownloadPermit {
+    limiter: DownloadLimiter,
+    host: String,
+}
+
+impl DownloadLimiter {
+    pub fn new(max_in_flight: usize, max_per_host: usize) -> Self {
+        DownloadLimiter {
+            max_in_flight,
+            max_per_host,
+            state: Arc::new(Mutex::new(LimiterState::default())),
+        }
+    }
+
+    /// Resolves when a download from `host` may start.
+    pub fn acquire(&self, host: &str) -> Box<dyn Future<Item = DownloadPermit, Error = ()>> {
+        let mut state = self.state.lock().unwrap();
+        if self.has_capacity(&state, host) {
+            state.take(host);
+            return Box::new(ok(self.permit(host)));
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push_back((host.to_owned(), sender));
+        Box::new(receiver.map_err(|_| ()))
+    }
+
+    fn has_capacity(&self, state: &LimiterState, host: &str) -> bool {
+        (self.max_in_flight == 0 || state.in_flight < self.max_in_flight)
+            && (self.max_per_host == 0
+                || state.per_host.get(host).cloned().unwrap_or(0) < self.max_per_host)
+    }
+
+    fn permit(&self, host: &str) -> DownloadPermit {
+        DownloadPermit {
+            limiter: self.clone(),
+            host: host.to_owned(),
+        }
+    }
+
+    /// Frees the slot of `host` and hands it to the downloads which waited longest,
+    /// skipping the ones whose host is still at its limit.
+    fn release(&self, host: &str) {
+        let mut granted = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            state.give_back(host);
+            let mut i = 0;
+            while i < state.waiting.len() {
+                if self.max_in_flight != 0 && state.in_flight >= self.max_in_flight {
+                    break;
+                }
+                if self.has_capacity(&state, &state.waiting[i].0) {
+                    let (host, sender) = state.waiting.remove(i).unwrap();
+                    state.take(&host);
+                    granted.push((host, sender));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        // sent without the lock, a permit of a download which was cancelled meanwhile
+        // is dropped and released again.
+        for (host, sender) in granted {
+            let _ = sender.send(self.permit(&host));
+        }
+    }
+}
+
+impl LimiterState {
+    fn take(&mut self, host: &str) {
+        self.in_flight += 1;
+        *self.per_host.entry(host.to_owned()).or_insert(0) += 1;
+    }
+
+    fn give_back(&mut self, host: &str) {
+        self.in_flight -= 1;
+        if let Some(count) = self.per_host.get_mut(host) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_host.remove(host);
+            }
+        }
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify, Spawn};
+    use futures::Async;
+
+    struct NoopNotify;
+
+    impl Notify for NoopNotify {
+        fn notify(&self, _: usize) {}
+    }
+
+    type Acquire = Spawn<Box<dyn Future<Item = DownloadPermit, Error = ()>>>;
+
+    fn acquire(limiter: &DownloadLimiter, host: &str) -> Acquire {
+        executor::spawn(limiter.acquire(host))
+    }
+
+    fn poll(acquire: &mut Acquire) -> Option<DownloadPermit> {
+        match acquire.poll_future_notify(&Arc::new(NoopNotify), 0) {
+            Ok(Async::Ready(permit)) => Some(permit),
+            Ok(Async::NotReady) => None,
+            Err(_) => panic!("permit dropped"),
+        }
+    }
+
+    #[test]
+    fn test_per_host_limit() {
+        let limiter = DownloadLimiter::new(0, 2);
+        let first = poll(&mut acquire(&limiter, "a.example.com")).unwrap();
+        let _second = poll(&mut acquire(&limiter, "a.example.com")).unwrap();
+        let mut third = acquire(&limiter, "a.example.com");
+        assert!(poll(&mut third).is_none());
+        // other hosts are not held up.
+        assert!(poll(&mut acquire(&limiter, "b.example.com")).is_some());
+        drop(first);
+        assert!(poll(&mut third).is_some());
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let limiter = DownloadLimiter::new(2, 0);
+        let first = poll(&mut acquire(&limiter, "a.example.com")).unwrap();
+        let second = poll(&mut acquire(&limiter, "b.example.com")).unwrap();
+        let mut third = acquire(&limiter, "c.example.com");
+        let mut fourth = acquire(&limiter, "d.example.com");
+        assert!(poll(&mut third).is_none());
+        drop(second);
+        assert!(poll(&mut fourth).is_none());
+        let _third = poll(&mut third).unwrap();
+        assert!(poll(&mut fourth).is_none());
+        drop(first);
+        assert!(poll(&mut fourth).is_some());
+    }
+
+    #[test]
+    fn test_waiting_order() {
+        let limiter = DownloadLimiter::new(2, 1);
+        let a = poll(&mut acquire(&limiter, "a.example.com")).unwrap();
+        let _b = poll(&mut acquire(&limiter, "b.example.com")).unwrap();
+        let mut next_a = acquire(&limiter, "a.example.com");
+        let mut c = acquire(&limiter, "c.example.com");
+        drop(a);
+        // the freed slot goes to the download waiting longest.
+        let _next_a = poll(&mut next_a).unwrap();
+        assert!(poll(&mut c).is_none());
+    }
+
+    #[test]
+    fn test_cancelled_waiter() {
+        let limiter = DownloadLimiter::new(1, 0);
+        let first = poll(&mut acquire(&limiter, "a.example.com")).unwrap();
+        let cancelled = acquire(&limiter, "a.example.com");
+        let mut waiting = acquire(&limiter, "a.example.com");
+        drop(cancelled);
+        drop(first);
+        assert!(poll(&mut waiting).is_some());
+    }
+}
@@ -0,0 +1,148 @@
+//! EXIF orientation support. Cameras store pixels as captured by the sensor and record
+//! the rotation needed for display in the EXIF Orientation tag, which decoders ignore.
+
+const JPEG_SOI: [u8; 2] = [0xff, 0xd8];
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_SOS: u8 = 0xda;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+const SHORT_TYPE: u16 = 3;
+
+/// Reads the EXIF orientation (1 to 8) of a JPEG image. `None` if the image is not a JPEG,
+/// has no EXIF data or the tag is missing or invalid.
+pub fn exif_orientation(bytes: &[u8]) -> Option<u16> {
+    if !bytes.starts_with(&JPEG_SOI) {
+        return None;
+    }
+    let mut pos = JPEG_SOI.len();
+    // walk marker segments until the image data starts.
+    while pos + 4 <= bytes.len() && bytes[pos] == 0xff {
+        let marker = bytes[pos + 1];
+        if marker == JPEG_SOS {
+            return None;
+        }
+        let length = usize::from(read_u16(&bytes[pos + 2..], false)?);
+        let segment = bytes.get(pos + 4..pos + 2 + length)?;
+        if marker == JPEG_APP1 && segment.starts_with(EXIF_HEADER) {
+            return tiff_orientation(&segment[EXIF_HEADER.len()..]);
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let ifd_offset = read_u32(tiff.get(4..)?, little_endian)? as usize;
+    let entry_count = usize::from(read_u16(tiff.get(ifd_offset..)?, little_endian)?);
+    for i in 0..entry_count {
+        let entry = tiff.get(ifd_offset + 2 + i * 12..ifd_offset + 14 + i * 12)?;
+        if read_u16(entry, little_endian)? == ORIENTATION_TAG {
+            if read_u16(&entry[2..], little_endian)? != SHORT_TYPE {
+                return None;
+            }
+            let orientation = read_u16(&entry[8..], little_endian)?;
+            return if (1..=8).contains(&orientation) {
+                Some(orientation)
+            } else {
+                None
+            };
+        }
+    }
+    None
+}
+
+fn read_u16(bytes: &[u8], little_endian: bool) -> Option<u16> {
+    let b = bytes.get(..2)?;
+    Some(if little_endian {
+        u16::from(b[0]) | u16::from(b[1]) << 8
+    } else {
+        u16::from(b[0]) << 8 | u16::from(b[1])
+    })
+}
+
+fn read_u32(bytes: &[u8], little_endian: bool) -> Option<u32> {
+    let hi_lo = (
+        read_u16(bytes, little_endian)?,
+        read_u16(bytes.get(2..)?, little_endian)?,
+    );
+    Some(if little_endian {
+        u32::from(hi_lo.1) << 16 | u32::from(hi_lo.0)
+    } else {
+        u32::from(hi_lo.0) << 16 | u32::from(hi_lo.1)
+    })
+}
+
+/// Rotates and flips stored pixels so the image is displayed upright.
+pub fn apply_orientation(img: image::DynamicImage, orientation: u16) -> image::DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    // Every `orientation_N.jpg` stores the pixels of `expected.png` as a camera would
+    // with EXIF orientation N, so all of them must look like `expected.png` once oriented.
+    #[test]
+    fn test_golden_orientations() {
+        let expected = image::open("test_data/in/exif_orientation/expected.png")
+            .unwrap()
+            .to_rgb();
+        for orientation in 1..=8 {
+            let bytes = std::fs::read(format!(
+                "test_data/in/exif_orientation/orientation_{}.jpg",
+                orientation
+            ))
+            .unwrap();
+            assert_eq!(exif_orientation(&bytes), Some(orientation));
+            let img = apply_orientation(image::load_from_memory(&bytes).unwrap(), orientation);
+            assert_eq!(
+                img.dimensions(),
+                expected.dimensions(),
+                "orientation {}",
+                orientation
+            );
+            let img = img.to_rgb();
+            let diff = img
+                .pixels()
+                .zip(expected.pixels())
+                .flat_map(|(a, b)| {
+                    a.data
+                        .iter()
+                        .zip(b.data.iter())
+                        .map(|(a, b)| (i32::from(*a) - i32::from(*b)).abs())
+                        .collect::<Vec<_>>()
+                })
+                .sum::<i32>() as f64
+                / f64::from(img.width() * img.height() * 3);
+            assert!(
+                diff < 8.0,
+                "orientation {} mean difference {}",
+                orientation,
+                diff
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_orientation() {
+        let png = std::fs::read("test_data/in/exif_orientation/expected.png").unwrap();
+        assert_eq!(exif_orientation(&png), None);
+        assert_eq!(exif_orientation(&[0xff, 0xd8, 0xff, 0xe1, 0x00]), None);
+    }
+}
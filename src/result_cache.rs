@@ -0,0 +1,234 @@
+use crate::thumbnail::ThumbnailOptions;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Stored thumbnail paths by image url and thumbnail options, shared by all workers,
+/// so repeat requests skip downloading the image. Least recently used entries are
+/// dropped first.
+#[derive(Debug, Clone)]
+pub struct ResultCache {
+    /// 0 disables the cache.
+    capacity: usize,
+    /// Time an entry is used for, zero disables the cache.
+    ttl: Duration,
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResult {
+    /// Path of the stored thumbnail relative to the storage base path.
+    pub path: String,
+    /// Url the image was downloaded from, if the request was redirected.
+    pub final_url: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// Keys by the tick they were last used at.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    result: CachedResult,
+    expires: Instant,
+    used: u64,
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        ResultCache {
+            capacity,
+            ttl,
+            state: Arc::new(Mutex::new(CacheState::default())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 && self.ttl > Duration::from_secs(0)
+    }
+
+    pub fn get(&self, url: &str, opt: &ThumbnailOptions) -> Option<CachedResult> {
+        self.get_at(url, opt, Instant::now())
+    }
+
+    fn get_at(&self, url: &str, opt: &ThumbnailOptions, now: Instant) -> Option<CachedResult> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let key = cache_key(url, opt)?;
+        let mut state = self.state.lock().unwrap();
+        let (expires, used) = match state.entries.get(&key) {
+            Some(entry) => (entry.expires, entry.used),
+            None => return None,
+        };
+        state.recency.remove(&used);
+        if expires <= now {
+            state.entries.remove(&key);
+            return None;
+        }
+        let used = state.touch(&key);
+        let entry = state.entries.get_mut(&key)?;
+        entry.used = used;
+        Some(entry.result.clone())
+    }
+
+    pub fn insert(&self, url: &str, opt: &ThumbnailOptions, result: CachedResult) {
+        self.insert_at(url, opt, result, Instant::now())
+    }
+
+    fn insert_at(&self, url: &str, opt: &ThumbnailOptions, result: CachedResult, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let key = match cache_key(url, opt) {
+            Some(key) => key,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let used = state.touch(&key);
+        let entry = CacheEntry {
+            result,
+            expires: now + self.ttl,
+            used,
+        };
+        if let Some(replaced) = state.entries.insert(key, entry) {
+            state.recency.remove(&replaced.used);
+        }
+        while state.entries.len() > self.capacity {
+            let oldest = match state.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = state.recency.remove(&oldest) {
+                state.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn remove(&self, url: &str, opt: &ThumbnailOptions) {
+        if let Some(key) = cache_key(url, opt) {
+            let mut state = self.state.lock().unwrap();
+            if let Some(entry) = state.entries.remove(&key) {
+                state.recency.remove(&entry.used);
+            }
+        }
+    }
+}
+
+impl CacheState {
+    /// Marks `key` as the most recently used.
+    fn touch(&mut self, key: &str) -> u64 {
+        self.tick += 1;
+        self.recency.insert(self.tick, key.to_owned());
+        self.tick
+    }
+}
+
+/// Normalised url and the options, `None` for urls which can't be parsed.
+/// Scheme and host case, default ports and fragments don't make urls differ.
+fn cache_key(url: &str, opt: &ThumbnailOptions) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    url.set_fragment(None);
+    Some(format!("{} {:?}", url, opt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{EncoderOptions, OutputFormat, PngCompression};
+    use crate::thumbnail::{Color, Gravity, ResizeMode};
+
+    fn options(width: u32) -> ThumbnailOptions {
+        ThumbnailOptions {
+            width,
+            height: 100,
+            mode: ResizeMode::Exact,
+            background: Color([0xff, 0xff, 0xff, 0xff]),
+            gravity: Gravity::Center,
+            focal_point: None,
+            format: OutputFormat::Jpeg,
+            encoder: EncoderOptions {
+                jpeg_quality: 75,
+                jpeg_progressive: false,
+                png_compression: PngCompression::Default,
+                webp_lossless: false,
+                webp_quality: 75,
+                avif_quality: 60,
+                avif_speed: 8,
+            },
+        }
+    }
+
+    fn result(path: &str) -> CachedResult {
+        CachedResult {
+            path: path.to_owned(),
+            final_url: None,
+        }
+    }
+
+    #[test]
+    fn test_lru() {
+        let cache = ResultCache::new(2, Duration::from_secs(60));
+        cache.insert("http://example.com/a.jpg", &options(100), result("a"));
+        cache.insert("http://example.com/b.jpg", &options(100), result("b"));
+        assert!(cache
+            .get("http://example.com/a.jpg", &options(100))
+            .is_some());
+        // b is the least recently used.
+        cache.insert("http://example.com/c.jpg", &options(100), result("c"));
+        assert_eq!(cache.get("http://example.com/b.jpg", &options(100)), None);
+        assert_eq!(
+            cache.get("http://example.com/a.jpg", &options(100)),
+            Some(result("a"))
+        );
+        assert_eq!(
+            cache.get("http://example.com/c.jpg", &options(100)),
+            Some(result("c"))
+        );
+        assert_eq!(cache.get("http://example.com/a.jpg", &options(50)), None);
+        cache.remove("http://example.com/a.jpg", &options(100));
+        assert_eq!(cache.get("http://example.com/a.jpg", &options(100)), None);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let cache = ResultCache::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert_at("http://example.com/a.jpg", &options(100), result("a"), now);
+        let url = "http://example.com/a.jpg";
+        assert!(cache
+            .get_at(url, &options(100), now + Duration::from_secs(59))
+            .is_some());
+        assert_eq!(
+            cache.get_at(url, &options(100), now + Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(cache.get_at(url, &options(100), now), None);
+
+        let disabled = ResultCache::new(10, Duration::from_secs(0));
+        disabled.insert(url, &options(100), result("a"));
+        assert_eq!(disabled.get(url, &options(100)), None);
+    }
+
+    #[test]
+    fn test_normalized_url() {
+        let cache = ResultCache::new(10, Duration::from_secs(60));
+        cache.insert(
+            "HTTP://Example.com:80/a.jpg#top",
+            &options(100),
+            result("a"),
+        );
+        assert_eq!(
+            cache.get("http://example.com/a.jpg", &options(100)),
+            Some(result("a"))
+        );
+        // paths and queries are case sensitive.
+        assert_eq!(cache.get("http://example.com/A.jpg", &options(100)), None);
+        assert_eq!(cache.get("not a url", &options(100)), None);
+    }
+}
@@ -0,0 +1,136 @@
+use actix_web::http::header::HttpDate;
+use std::time::{Duration, SystemTime};
+
+/// When and how often failed downloads are retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, 1 disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one.
+    pub base_delay: Duration,
+    /// Longest delay, also the longest `Retry-After` waited for.
+    pub max_delay: Duration,
+    /// Fraction of the delay, from 0 to 1, randomly taken off.
+    pub jitter: f64,
+    /// Response status codes of transient failures.
+    pub retryable_status_codes: Vec<u16>,
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts < 1 {
+            return Err("max retry attempts must be at least 1".to_owned());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(format!(
+                "retry jitter must be between 0 and 1, got {}",
+                self.jitter
+            ));
+        }
+        Ok(())
+    }
+
+    /// Delay before another attempt, `None` if attempt number `attempt` was the last one.
+    /// `retry_after` from the response is used instead of the backoff, unless it is
+    /// longer than `max_delay`.
+    pub fn delay(
+        &self,
+        attempt: u32,
+        retry_after: Option<Duration>,
+        random: f64,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt, random)),
+        }
+    }
+
+    /// Exponential backoff after attempt number `attempt`, `random` is between 0 and 1.
+    fn backoff(&self, attempt: u32, random: f64) -> Duration {
+        let exponential = self
+            .base_delay
+            .checked_mul(1 << (attempt - 1).min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        exponential.mul_f64(1.0 - self.jitter * random)
+    }
+
+    pub fn is_retryable_status(&self, code: u16) -> bool {
+        self.retryable_status_codes.contains(&code)
+    }
+}
+
+/// Parses a `Retry-After` header, given either in seconds or as an HTTP date.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date: SystemTime = value.parse::<HttpDate>().ok()?.into();
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            jitter: 0.5,
+            retryable_status_codes: vec![503],
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy();
+        assert_eq!(policy.delay(1, None, 0.0), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(2, None, 0.0), Some(Duration::from_millis(200)));
+        assert_eq!(policy.delay(3, None, 0.0), Some(Duration::from_millis(300)));
+        assert_eq!(policy.delay(4, None, 0.0), None);
+        assert_eq!(policy.delay(2, None, 1.0), Some(Duration::from_millis(100)));
+        assert_eq!(policy.backoff(100, 0.0), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let policy = policy();
+        let retry_after = Some(Duration::from_millis(250));
+        assert_eq!(policy.delay(1, retry_after, 1.0), retry_after);
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(1)), 0.0), None);
+        assert_eq!(policy.delay(4, retry_after, 0.0), None);
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut policy = policy();
+        assert_eq!(policy.validate(), Ok(()));
+        policy.jitter = 1.5;
+        assert!(policy.validate().is_err());
+        policy.jitter = 0.0;
+        policy.max_attempts = 0;
+        assert!(policy.validate().is_err());
+    }
+}
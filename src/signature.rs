@@ -0,0 +1,156 @@
+use failure::Fail;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use url::form_urlencoded;
+
+const SIGNATURE_PARAM: &str = "sig";
+const EXPIRY_PARAM: &str = "exp";
+
+/// Signs and verifies query strings with HMAC-SHA256.
+///
+/// The signature covers every query parameter except `sig` itself, so the source url,
+/// the thumbnail parameters and the optional `exp` unix timestamp can't be changed.
+/// Parameters are sorted and url encoded again before signing, the order in the url
+/// doesn't matter.
+#[derive(Clone)]
+pub struct UrlSigner {
+    /// Active keys, any of them verifies a signature.
+    keys: Vec<Vec<u8>>,
+}
+
+impl UrlSigner {
+    pub fn new(keys: &[String]) -> Self {
+        UrlSigner {
+            keys: keys.iter().map(|key| key.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    /// Checks the `sig` parameter against every active key and the `exp` parameter against `now`.
+    pub fn verify(&self, query: &str, now: u64) -> Result<(), SignatureError> {
+        let signature = form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == SIGNATURE_PARAM)
+            .map(|(_, value)| value.into_owned())
+            .ok_or(SignatureError::Missing)?;
+        let signature = decode_hex(&signature).ok_or(SignatureError::Invalid)?;
+        let canonical = canonical_query(query);
+        if !self
+            .keys
+            .iter()
+            .any(|key| mac(key, &canonical).verify_slice(&signature).is_ok())
+        {
+            return Err(SignatureError::Invalid);
+        }
+        // the expiry is checked after the signature, so it is known to be genuine.
+        if let Some((_, expiry)) =
+            form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == EXPIRY_PARAM)
+        {
+            let expiry = expiry.parse::<u64>().map_err(|_| SignatureError::Invalid)?;
+            if now > expiry {
+                return Err(SignatureError::Expired);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UrlSigner {{ keys: {} }}", self.keys.len())
+    }
+}
+
+fn mac(key: &[u8], message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    mac
+}
+
+fn canonical_query(query: &str) -> String {
+    let mut params = form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| name != SIGNATURE_PARAM)
+        .collect::<Vec<_>>();
+    params.sort();
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum SignatureError {
+    #[fail(display = "Missing signature")]
+    Missing,
+    #[fail(display = "Invalid signature")]
+    Invalid,
+    #[fail(display = "Signature expired")]
+    Expired,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "url=https%3A%2F%2Fpicsum.photos%2Fid%2F1%2F500%2F500&w=200&h=200";
+
+    fn signer(keys: &[&str]) -> UrlSigner {
+        UrlSigner::new(&keys.iter().map(|key| key.to_string()).collect::<Vec<_>>())
+    }
+
+    impl UrlSigner {
+        pub(crate) fn sign(&self, query: &str) -> String {
+            let mac = mac(&self.keys[0], &canonical_query(query));
+            mac.finalize()
+                .into_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let signer = signer(&["secret"]);
+        let signed = format!("{}&sig={}", QUERY, signer.sign(QUERY));
+        assert_eq!(signer.verify(&signed, 0), Ok(()));
+        // parameter order doesn't matter.
+        let reordered = format!(
+            "sig={}&h=200&w=200&url=https://picsum.photos/id/1/500/500",
+            signer.sign(QUERY)
+        );
+        assert_eq!(signer.verify(&reordered, 0), Ok(()));
+        assert_eq!(signer.verify(QUERY, 0), Err(SignatureError::Missing));
+        let tampered = signed.replace("w=200", "w=2000");
+        assert_eq!(signer.verify(&tampered, 0), Err(SignatureError::Invalid));
+        let garbage = format!("{}&sig=zz", QUERY);
+        assert_eq!(signer.verify(&garbage, 0), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let signer = signer(&["secret"]);
+        let query = format!("{}&exp=1000", QUERY);
+        let signed = format!("{}&sig={}", query, signer.sign(&query));
+        assert_eq!(signer.verify(&signed, 1000), Ok(()));
+        assert_eq!(signer.verify(&signed, 1001), Err(SignatureError::Expired));
+        let extended = signed.replace("exp=1000", "exp=2000");
+        assert_eq!(signer.verify(&extended, 1001), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = signer(&["old"]);
+        let rotated = signer(&["new", "old"]);
+        let signed_old = format!("{}&sig={}", QUERY, old.sign(QUERY));
+        let signed_new = format!("{}&sig={}", QUERY, rotated.sign(QUERY));
+        assert_eq!(rotated.verify(&signed_old, 0), Ok(()));
+        assert_eq!(rotated.verify(&signed_new, 0), Ok(()));
+        assert_eq!(old.verify(&signed_new, 0), Err(SignatureError::Invalid));
+    }
+}
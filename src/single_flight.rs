@@ -0,0 +1,180 @@
+use futures::future::{Either, Future, IntoFuture};
+use futures::sync::oneshot;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+type Waiters<T, E> = Arc<Mutex<HashMap<String, Vec<oneshot::Sender<Result<T, E>>>>>>;
+
+/// Runs an operation once for all callers asking for the same key at the same time,
+/// across all workers. Later callers wait for the result of the first one.
+pub struct SingleFlight<T, E> {
+    waiters: Waiters<T, E>,
+}
+
+/// Removes its key once the operation finished and hands the result to the waiters.
+/// Dropped without a result, the waiters run the operation themselves.
+struct Flight<T, E> {
+    waiters: Waiters<T, E>,
+    key: String,
+    done: bool,
+}
+
+impl<T: Clone + 'static, E: Clone + 'static> SingleFlight<T, E> {
+    pub fn new() -> Self {
+        SingleFlight {
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Future of `operation`, or of the running operation with the same key.
+    pub fn run<F, O>(&self, key: &str, operation: O) -> Box<dyn Future<Item = T, Error = E>>
+    where
+        F: Future<Item = T, Error = E> + 'static,
+        O: FnOnce() -> F + 'static,
+    {
+        let mut waiters = self.waiters.lock().unwrap();
+        if let Some(key_waiters) = waiters.get_mut(key) {
+            let (sender, receiver) = oneshot::channel();
+            key_waiters.push(sender);
+            return Box::new(receiver.then(move |result| match result {
+                Ok(result) => Either::A(result.into_future()),
+                Err(_) => Either::B(operation()),
+            }));
+        }
+        waiters.insert(key.to_owned(), vec![]);
+        let flight = Flight {
+            waiters: self.waiters.clone(),
+            key: key.to_owned(),
+            done: false,
+        };
+        Box::new(operation().then(move |result| {
+            flight.finish(&result);
+            result
+        }))
+    }
+}
+
+impl<T: Clone, E: Clone> Flight<T, E> {
+    fn finish(mut self, result: &Result<T, E>) {
+        self.done = true;
+        let waiters = self.waiters.lock().unwrap().remove(&self.key);
+        for sender in waiters.unwrap_or_default() {
+            let _ = sender.send(result.clone());
+        }
+    }
+}
+
+impl<T, E> Drop for Flight<T, E> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.waiters.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+impl<T, E> Clone for SingleFlight<T, E> {
+    fn clone(&self) -> Self {
+        SingleFlight {
+            waiters: self.waiters.clone(),
+        }
+    }
+}
+
+impl<T, E> fmt::Debug for SingleFlight<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SingleFlight")
+            .field("in_flight", &self.waiters.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify, Spawn};
+    use futures::Async;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct NoopNotify;
+
+    impl Notify for NoopNotify {
+        fn notify(&self, _: usize) {}
+    }
+
+    type Operation = Box<dyn Future<Item = u32, Error = String>>;
+    type Run = Spawn<Operation>;
+
+    fn poll(run: &mut Run) -> Option<Result<u32, String>> {
+        match run.poll_future_notify(&Arc::new(NoopNotify), 0) {
+            Ok(Async::Ready(value)) => Some(Ok(value)),
+            Ok(Async::NotReady) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Operation which completes with what is sent, counting its runs.
+    fn operation(
+        runs: &Rc<Cell<u32>>,
+    ) -> (
+        oneshot::Sender<Result<u32, String>>,
+        impl FnOnce() -> Operation,
+    ) {
+        let (sender, receiver) = oneshot::channel();
+        let runs = runs.clone();
+        let operation = move || -> Operation {
+            runs.set(runs.get() + 1);
+            Box::new(
+                receiver
+                    .map_err(|_| "cancelled".to_owned())
+                    .and_then(|result| result),
+            )
+        };
+        (sender, operation)
+    }
+
+    #[test]
+    fn test_shared_result() {
+        let flights = SingleFlight::new();
+        let runs = Rc::new(Cell::new(0));
+        let (first_sender, first) = operation(&runs);
+        let (_, second) = operation(&runs);
+        let (_other_sender, other) = operation(&runs);
+        let mut first = executor::spawn(flights.run("a", first));
+        let mut second = executor::spawn(flights.run("a", second));
+        let mut other = executor::spawn(flights.run("b", other));
+        assert_eq!(poll(&mut first), None);
+        assert_eq!(poll(&mut second), None);
+        assert_eq!(poll(&mut other), None);
+        assert_eq!(runs.get(), 2);
+
+        first_sender.send(Err("failed".to_owned())).unwrap();
+        assert_eq!(poll(&mut first), Some(Err("failed".to_owned())));
+        assert_eq!(poll(&mut second), Some(Err("failed".to_owned())));
+
+        // finished operations run again.
+        let (sender, again) = operation(&runs);
+        let mut again = executor::spawn(flights.run("a", again));
+        sender.send(Ok(1)).unwrap();
+        assert_eq!(poll(&mut again), Some(Ok(1)));
+        assert_eq!(runs.get(), 3);
+    }
+
+    #[test]
+    fn test_cancelled_first_caller() {
+        let flights = SingleFlight::new();
+        let runs = Rc::new(Cell::new(0));
+        let (_first_sender, first) = operation(&runs);
+        let (second_sender, second) = operation(&runs);
+        let first = executor::spawn(flights.run("a", first));
+        let mut second = executor::spawn(flights.run("a", second));
+        assert_eq!(poll(&mut second), None);
+        drop(first);
+        // the waiting caller runs the operation itself.
+        assert_eq!(poll(&mut second), None);
+        second_sender.send(Ok(2)).unwrap();
+        assert_eq!(poll(&mut second), Some(Ok(2)));
+        assert_eq!(runs.get(), 2);
+    }
+}
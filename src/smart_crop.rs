@@ -0,0 +1,135 @@
+use image::GenericImageView;
+
+// Score weights of the crop window heuristic. Edges mark detailed areas, skin tones mark
+// people, saturation slightly prefers colorful subjects over flat backgrounds.
+const EDGE_WEIGHT: f64 = 1.0;
+const SKIN_WEIGHT: f64 = 60.0;
+const SATURATION_WEIGHT: f64 = 0.1;
+// Share of the score subtracted for the distance from the center, so flat images
+// and near ties still get a center crop.
+const CENTER_BIAS: f64 = 0.05;
+
+/// Chooses the most interesting `width`x`height` window of an image, which must be
+/// at least as large as the window. Returns the top left corner of the window.
+///
+/// Only one axis is expected to have room to move (the image is scaled to cover the
+/// window before cropping), but both axes are searched.
+pub fn crop_offset(img: &image::DynamicImage, width: u32, height: u32) -> (u32, u32) {
+    let (img_width, img_height) = img.dimensions();
+    let scores = pixel_scores(&img.to_rgb());
+    let column_scores = (0..img_width)
+        .map(|x| {
+            (0..img_height)
+                .map(|y| scores[(y * img_width + x) as usize])
+                .sum()
+        })
+        .collect::<Vec<f64>>();
+    let row_scores = (0..img_height)
+        .map(|y| {
+            (0..img_width)
+                .map(|x| scores[(y * img_width + x) as usize])
+                .sum()
+        })
+        .collect::<Vec<f64>>();
+    (
+        best_window(&column_scores, width as usize) as u32,
+        best_window(&row_scores, height as usize) as u32,
+    )
+}
+
+/// Start of the window of `size` consecutive values with the highest biased sum.
+fn best_window(values: &[f64], size: usize) -> usize {
+    let excess = values.len() - size;
+    if excess == 0 {
+        return 0;
+    }
+    let total: f64 = values.iter().sum();
+    let mut window: f64 = values[..size].iter().sum();
+    let mut best = (0, f64::MIN);
+    for start in 0..=excess {
+        if start > 0 {
+            window += values[start + size - 1] - values[start - 1];
+        }
+        let distance = (start as f64 - excess as f64 / 2.0).abs() / excess as f64;
+        let score = window - total * CENTER_BIAS * distance;
+        if score > best.1 {
+            best = (start, score);
+        }
+    }
+    best.0
+}
+
+fn pixel_scores(img: &image::RgbImage) -> Vec<f64> {
+    let (width, height) = img.dimensions();
+    let luma = |x: u32, y: u32| {
+        let [r, g, b] = img.get_pixel(x, y).data;
+        0.299 * f64::from(r) + 0.587 * f64::from(g) + 0.114 * f64::from(b)
+    };
+    let mut scores = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b] = img.get_pixel(x, y).data;
+            let center = luma(x, y);
+            let edge = (center - luma((x + 1).min(width - 1), y)).abs()
+                + (center - luma(x, (y + 1).min(height - 1))).abs();
+            let max = r.max(g).max(b);
+            let min = r.min(g).min(b);
+            let saturation = f64::from(max - min);
+            let skin = if is_skin_tone(r, g, b) { 1.0 } else { 0.0 };
+            scores.push(EDGE_WEIGHT * edge + SKIN_WEIGHT * skin + SATURATION_WEIGHT * saturation);
+        }
+    }
+    scores
+}
+
+// RGB skin tone rule of Kovac et al. for uniform daylight illumination.
+fn is_skin_tone(r: u8, g: u8, b: u8) -> bool {
+    let (r, g, b) = (i16::from(r), i16::from(g), i16::from(b));
+    r > 95 && g > 40 && b > 20 && r > g && r > b && r - g.min(b) > 15 && (r - g).abs() > 15
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKGROUND: image::Rgb<u8> = image::Rgb {
+        data: [90, 110, 130],
+    };
+
+    #[test]
+    fn test_detailed_area_is_kept() {
+        // flat background with a checkerboard patch near the right edge.
+        let img = image::RgbImage::from_fn(300, 100, |x, y| {
+            if (230..280).contains(&x) && (30..70).contains(&y) && (x / 4 + y / 4) % 2 == 0 {
+                image::Rgb([250, 250, 250])
+            } else {
+                BACKGROUND
+            }
+        });
+        let (x, y) = crop_offset(&image::DynamicImage::ImageRgb8(img), 100, 100);
+        assert_eq!(y, 0);
+        assert!((180..=200).contains(&x), "window at {}", x);
+    }
+
+    #[test]
+    fn test_skin_tone_is_kept() {
+        // flat skin colored area in the top part of a tall image.
+        let img = image::RgbImage::from_fn(100, 400, |_, y| {
+            if (20..90).contains(&y) {
+                image::Rgb([224, 172, 140])
+            } else {
+                BACKGROUND
+            }
+        });
+        let (x, y) = crop_offset(&image::DynamicImage::ImageRgb8(img), 100, 100);
+        assert_eq!(x, 0);
+        assert!(y <= 20, "window at {}", y);
+    }
+
+    #[test]
+    fn test_flat_image_is_center_cropped() {
+        let img = image::RgbImage::from_pixel(300, 100, BACKGROUND);
+        let (x, y) = crop_offset(&image::DynamicImage::ImageRgb8(img), 100, 100);
+        assert_eq!((x, y), (100, 0));
+    }
+}
@@ -0,0 +1,166 @@
+use std::str::FromStr;
+
+/// Image formats recognized from the first bytes of downloaded content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Bmp,
+    Tiff,
+    Ico,
+    Avif,
+}
+
+impl InputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            InputFormat::Jpeg => "jpeg",
+            InputFormat::Png => "png",
+            InputFormat::Gif => "gif",
+            InputFormat::Webp => "webp",
+            InputFormat::Bmp => "bmp",
+            InputFormat::Tiff => "tiff",
+            InputFormat::Ico => "ico",
+            InputFormat::Avif => "avif",
+        }
+    }
+
+    /// Format of a `Content-Type` header value, parameters are ignored.
+    pub fn from_mime_type(content_type: &str) -> Option<Self> {
+        let mime_type = content_type.split(';').next()?.trim().to_lowercase();
+        match mime_type.as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(InputFormat::Jpeg),
+            "image/png" | "image/x-png" => Some(InputFormat::Png),
+            "image/gif" => Some(InputFormat::Gif),
+            "image/webp" => Some(InputFormat::Webp),
+            "image/bmp" | "image/x-bmp" | "image/x-ms-bmp" => Some(InputFormat::Bmp),
+            "image/tiff" | "image/tiff-fx" => Some(InputFormat::Tiff),
+            "image/x-icon" | "image/vnd.microsoft.icon" => Some(InputFormat::Ico),
+            "image/avif" => Some(InputFormat::Avif),
+            _ => None,
+        }
+    }
+}
+
+/// Bytes of the body needed to detect the format, the signature is checked once they arrived.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Detects the image format from its signature.
+pub fn sniff(bytes: &[u8]) -> Option<InputFormat> {
+    let starts_with = |signature: &[u8]| bytes.starts_with(signature);
+    if starts_with(b"\xFF\xD8\xFF") {
+        Some(InputFormat::Jpeg)
+    } else if starts_with(b"\x89PNG\r\n\x1A\n") {
+        Some(InputFormat::Png)
+    } else if starts_with(b"GIF87a") || starts_with(b"GIF89a") {
+        Some(InputFormat::Gif)
+    } else if starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some(InputFormat::Webp)
+    } else if starts_with(b"BM") && bytes.len() >= 14 {
+        Some(InputFormat::Bmp)
+    } else if starts_with(b"II*\0") || starts_with(b"MM\0*") {
+        Some(InputFormat::Tiff)
+    } else if starts_with(b"\0\0\x01\0")
+        && matches!(bytes.get(4..6), Some(count) if count != [0, 0])
+    {
+        Some(InputFormat::Ico)
+    } else if is_avif(bytes) {
+        Some(InputFormat::Avif)
+    } else {
+        None
+    }
+}
+
+/// AVIF files start with an ISO-BMFF `ftyp` box listing `avif` or `avis`
+/// as the major or a compatible brand.
+fn is_avif(bytes: &[u8]) -> bool {
+    if bytes.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+    let box_size = match bytes.get(0..4) {
+        Some(size) => u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize,
+        None => return false,
+    };
+    let brands = match bytes.get(8..box_size.min(bytes.len())) {
+        Some(brands) => brands,
+        None => return false,
+    };
+    // major brand, minor version, then compatible brands.
+    brands
+        .chunks(4)
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .any(|(_, brand)| brand == b"avif" || brand == b"avis")
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(InputFormat::Jpeg),
+            "png" => Ok(InputFormat::Png),
+            "gif" => Ok(InputFormat::Gif),
+            "webp" => Ok(InputFormat::Webp),
+            "bmp" => Ok(InputFormat::Bmp),
+            "tiff" | "tif" => Ok(InputFormat::Tiff),
+            "ico" => Ok(InputFormat::Ico),
+            "avif" => Ok(InputFormat::Avif),
+            _ => Err(format!("unknown input format '{}'", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        let cases: Vec<(&[u8], Option<InputFormat>)> = vec![
+            (b"\xFF\xD8\xFF\xE0\0\x10JFIF", Some(InputFormat::Jpeg)),
+            (b"\x89PNG\r\n\x1A\n\0\0\0\rIHDR", Some(InputFormat::Png)),
+            (b"GIF89a\x01\0\x01\0", Some(InputFormat::Gif)),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Some(InputFormat::Webp)),
+            (b"BM\x36\0\0\0\0\0\0\0\x36\0\0\0", Some(InputFormat::Bmp)),
+            (b"II*\0\x08\0\0\0", Some(InputFormat::Tiff)),
+            (b"MM\0*\0\0\0\x08", Some(InputFormat::Tiff)),
+            (b"\0\0\x01\0\x01\0\x10\x10", Some(InputFormat::Ico)),
+            (
+                b"\0\0\0\x1Cftypavif\0\0\0\0avifmif1miaf",
+                Some(InputFormat::Avif),
+            ),
+            (
+                b"\0\0\0\x18ftypmif1\0\0\0\0mif1avis",
+                Some(InputFormat::Avif),
+            ),
+            (b"\0\0\0\x18ftypheic\0\0\0\0mif1heic", None),
+            (b"<!DOCTYPE html><html>", None),
+            (b"RIFF\x24\0\0\0WAVEfmt ", None),
+            (b"BM", None),
+            (b"", None),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(sniff(bytes), expected, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_from_mime_type() {
+        assert_eq!(
+            InputFormat::from_mime_type("image/JPEG; charset=binary"),
+            Some(InputFormat::Jpeg)
+        );
+        assert_eq!(
+            InputFormat::from_mime_type("image/vnd.microsoft.icon"),
+            Some(InputFormat::Ico)
+        );
+        assert_eq!(InputFormat::from_mime_type("image/svg+xml"), None);
+        assert_eq!(
+            InputFormat::from_mime_type("application/octet-stream"),
+            None
+        );
+    }
+}
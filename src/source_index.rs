@@ -0,0 +1,110 @@
+use crate::download::Validators;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Validators and content hash of downloaded source images by url, shared by all workers,
+/// so repeat requests can download an image only if it changed.
+#[derive(Debug, Clone)]
+pub struct SourceIndex {
+    /// Urls kept, the oldest are dropped first. 0 disables the index.
+    capacity: usize,
+    state: Arc<Mutex<IndexState>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceEntry {
+    pub validators: Validators,
+    /// Storage hash of the downloaded content.
+    pub source_hash: String,
+}
+
+#[derive(Debug, Default)]
+struct IndexState {
+    entries: HashMap<String, SourceEntry>,
+    /// Urls in the order they were added.
+    order: VecDeque<String>,
+}
+
+impl SourceIndex {
+    pub fn new(capacity: usize) -> Self {
+        SourceIndex {
+            capacity,
+            state: Arc::new(Mutex::new(IndexState::default())),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Option<SourceEntry> {
+        self.state.lock().unwrap().entries.get(url).cloned()
+    }
+
+    /// Adds or replaces the entry of `url`, entries without validators are not kept.
+    pub fn insert(&self, url: &str, entry: SourceEntry) {
+        if self.capacity == 0 || entry.validators.is_empty() {
+            self.remove(url);
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.entries.insert(url.to_owned(), entry).is_none() {
+            state.order.push_back(url.to_owned());
+        }
+        while state.order.len() > self.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn remove(&self, url: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.remove(url).is_some() {
+            state.order.retain(|kept| kept != url);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(etag: &str) -> SourceEntry {
+        SourceEntry {
+            validators: Validators {
+                etag: Some(etag.to_owned()),
+                last_modified: None,
+            },
+            source_hash: format!("hash-{}", etag),
+        }
+    }
+
+    #[test]
+    fn test_insert() {
+        let index = SourceIndex::new(2);
+        index.insert("a", entry("1"));
+        index.insert("b", entry("2"));
+        index.insert("a", entry("3"));
+        assert_eq!(index.get("a"), Some(entry("3")));
+        // the oldest url is dropped.
+        index.insert("c", entry("4"));
+        assert_eq!(index.get("a"), None);
+        assert_eq!(index.get("b"), Some(entry("2")));
+        assert_eq!(index.get("c"), Some(entry("4")));
+
+        index.insert(
+            "b",
+            SourceEntry {
+                validators: Validators::default(),
+                source_hash: "hash".to_owned(),
+            },
+        );
+        assert_eq!(index.get("b"), None);
+        index.remove("c");
+        assert_eq!(index.get("c"), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let index = SourceIndex::new(0);
+        index.insert("a", entry("1"));
+        assert_eq!(index.get("a"), None);
+    }
+}
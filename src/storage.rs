@@ -1,36 +1,72 @@
+use crate::encoder::{EncoderOptions, OutputFormat};
+use crate::thumbnail::ThumbnailOptions;
 use failure::Fail;
-use image;
 use log::*;
 use md5;
 use std::fs;
 use std::path::{Path, PathBuf};
 
 pub trait StorageService: Send + Sync {
-    fn get_image_handle(&self, bytes: impl AsRef<[u8]>) -> Result<ImageHandle, StorageError>;
-    fn store_image(
+    fn source_hash(&self, bytes: impl AsRef<[u8]>) -> String;
+    /// Handle of a thumbnail which is also stored in the `alternatives` formats.
+    fn get_image_handle(
         &self,
-        handle: &ImageHandle,
-        img: image::DynamicImage,
-    ) -> Result<(), StorageError>;
+        source_hash: &str,
+        opt: &ThumbnailOptions,
+        alternatives: &[OutputFormat],
+    ) -> Result<ImageHandle, StorageError>;
+    /// Handle of the same thumbnail stored in another format, served by content negotiation.
+    fn get_alternative_handle(&self, handle: &ImageHandle, format: OutputFormat) -> ImageHandle;
+    /// Handle of a stored thumbnail by its path relative to the base path.
+    fn get_stored_handle(&self, path: &str) -> Result<ImageHandle, StorageError>;
+    fn store_image(&self, handle: &ImageHandle, bytes: &[u8]) -> Result<(), StorageError>;
     fn get_base_path(&self) -> PathBuf;
 }
 
 #[derive(Debug, Clone)]
 pub struct ThumbnailStorage {
     base_path: PathBuf,
-    sub_path: PathBuf,
-    full_path: PathBuf,
-    ext: String,
+    /// Encoder settings which are not written into file names.
+    default_encoder: EncoderOptions,
 }
 
 impl StorageService for ThumbnailStorage {
-    fn get_image_handle(&self, bytes: impl AsRef<[u8]>) -> Result<ImageHandle, StorageError> {
-        let img_filename = format!("{:x}.{}", md5::compute(bytes.as_ref()), &self.ext).to_owned();
-        let img_full_path = self.full_path.join(&img_filename).to_owned();
+    fn source_hash(&self, bytes: impl AsRef<[u8]>) -> String {
+        format!("{:x}", md5::compute(bytes.as_ref()))
+    }
+
+    fn get_image_handle(
+        &self,
+        source_hash: &str,
+        opt: &ThumbnailOptions,
+        alternatives: &[OutputFormat],
+    ) -> Result<ImageHandle, StorageError> {
+        // alternatives are found from this name, so it tells their settings apart too,
+        // e.g. `hash_webp-q50.jpg`.
+        let alternative_variants = alternatives.iter().filter_map(|&format| {
+            opt.encoder
+                .variant(format, &self.default_encoder)
+                .map(|variant| format!("{}-{}", format.extension(), variant))
+        });
+        let variants = opt
+            .variant()
+            .into_iter()
+            .chain(opt.encoder.variant(opt.format, &self.default_encoder))
+            .chain(alternative_variants)
+            .collect::<Vec<_>>();
+        let img_filename = if variants.is_empty() {
+            format!("{}.{}", source_hash, opt.format.extension())
+        } else {
+            format!(
+                "{}_{}.{}",
+                source_hash,
+                variants.join("_"),
+                opt.format.extension()
+            )
+        };
+        let img_path = Path::new(&opt.dimensions()).join(&img_filename);
         return Ok(ImageHandle {
-            path: self
-                .sub_path
-                .join(&img_filename)
+            path: img_path
                 .to_str()
                 .ok_or(StorageError::InvalidPath)
                 .map_err(|err| {
@@ -38,41 +74,55 @@ impl StorageService for ThumbnailStorage {
                     err
                 })?
                 .to_owned(),
-            exists: img_full_path.is_file(),
+            exists: self.base_path.join(&img_path).is_file(),
+        });
+    }
+
+    // alternatives keep the whole original file name, e.g. `100x100/hash.jpg.webp`,
+    // so they can be found from the served path alone.
+    fn get_alternative_handle(&self, handle: &ImageHandle, format: OutputFormat) -> ImageHandle {
+        let path = format!("{}.{}", handle.path, format.extension());
+        ImageHandle {
+            exists: self.base_path.join(&path).is_file(),
+            path,
+        }
+    }
+
+    fn get_stored_handle(&self, path: &str) -> Result<ImageHandle, StorageError> {
+        let is_valid = path.split('/').all(|component| {
+            !component.is_empty() && !component.starts_with('.') && !component.contains('\\')
         });
+        if !is_valid {
+            debug!("invalid stored image path: {}", path);
+            return Err(StorageError::InvalidPath);
+        }
+        Ok(ImageHandle {
+            path: path.to_owned(),
+            exists: self.base_path.join(path).is_file(),
+        })
     }
 
     fn get_base_path(&self) -> PathBuf {
         self.base_path.clone()
     }
-    fn store_image(
-        &self,
-        handle: &ImageHandle,
-        img: image::DynamicImage,
-    ) -> Result<(), StorageError> {
-        handle.store_image(&self.base_path, img)
+    fn store_image(&self, handle: &ImageHandle, bytes: &[u8]) -> Result<(), StorageError> {
+        handle.store_image(&self.base_path, bytes)
     }
 }
 
 impl ThumbnailStorage {
     pub fn new(
         base_path: impl Into<PathBuf>,
-        img_width: u32,
-        img_height: u32,
-        img_ext: &str,
+        default_encoder: EncoderOptions,
     ) -> Result<Self, StorageError> {
         let base = base_path.into();
-        let sub_path = Path::new(&format!("{}x{}", img_width, img_height)).to_owned();
-        let full_path = base.join(&sub_path);
-        fs::create_dir_all(&full_path).map_err(|err| {
+        fs::create_dir_all(&base).map_err(|err| {
             error!("base storage folder creation error: {}", err);
             StorageError::FailedInit(err)
         })?;
         Ok(ThumbnailStorage {
             base_path: base,
-            sub_path: sub_path,
-            full_path: full_path,
-            ext: img_ext.to_owned(),
+            default_encoder,
         })
     }
 }
@@ -84,11 +134,29 @@ pub struct ImageHandle {
 }
 
 impl ImageHandle {
-    fn store_image(&self, base_path: &Path, img: image::DynamicImage) -> Result<(), StorageError> {
-        img.save(base_path.join(&self.path)).map_err(|err| {
-            error!("image store error: {}", err);
-            StorageError::FailedStore(err)
-        })
+    fn store_image(&self, base_path: &Path, bytes: &[u8]) -> Result<(), StorageError> {
+        let full_path = base_path.join(&self.path);
+        if let Some(dir) = full_path.parent() {
+            fs::create_dir_all(dir).map_err(|err| {
+                error!("thumbnail folder creation error: {}", err);
+                StorageError::FailedStore(err)
+            })?;
+        }
+        // written next to the final path and renamed, so readers never see a partial file.
+        // The name starts with a dot, stored paths of hidden files are never served.
+        let file_name = full_path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .ok_or(StorageError::InvalidPath)?;
+        let temp_path =
+            full_path.with_file_name(format!(".{}.{:x}.tmp", file_name, rand::random::<u64>()));
+        fs::write(&temp_path, bytes)
+            .and_then(|_| fs::rename(&temp_path, &full_path))
+            .map_err(|err| {
+                error!("image store error: {}", err);
+                let _ = fs::remove_file(&temp_path);
+                StorageError::FailedStore(err)
+            })
     }
 
     pub fn exists(&self) -> bool {
@@ -1,60 +1,548 @@
+use crate::encoder;
+use crate::orientation;
+use crate::smart_crop;
 use failure::Fail;
 use image::GenericImageView;
 use log::*;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
 
 pub trait ThumbnailService: Send + Sync {
+    fn load_image(&self, bytes: impl AsRef<[u8]>) -> Result<image::DynamicImage, ThumbnailError>;
     fn make_thumbnail(
         &self,
-        bytes: impl AsRef<[u8]>,
-    ) -> Result<image::DynamicImage, ThumbnailError>;
+        img: &image::DynamicImage,
+        opt: &ThumbnailOptions,
+    ) -> image::DynamicImage;
+    fn encode_thumbnail(
+        &self,
+        img: &image::DynamicImage,
+        opt: &ThumbnailOptions,
+    ) -> Result<Vec<u8>, ThumbnailError>;
 }
 
-#[derive(Debug, Clone)]
-pub struct ThumbnailCreator {
-    opt: ThumbnailOptions,
-}
+#[derive(Debug, Clone, Default)]
+pub struct ThumbnailCreator;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ThumbnailOptions {
     pub width: u32,
     pub height: u32,
-    pub exact_size: bool,
+    pub mode: ResizeMode,
+    /// Letterbox color, used by `ResizeMode::Pad` only.
+    pub background: Color,
+    /// Part of the image kept by `ResizeMode::Cover`, unless a focal point is set.
+    pub gravity: Gravity,
+    /// Point kept as close to the thumbnail center as possible by `ResizeMode::Cover`.
+    pub focal_point: Option<FocalPoint>,
+    pub format: encoder::OutputFormat,
+    pub encoder: encoder::EncoderOptions,
+}
+
+/// How the image is fitted into the thumbnail box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Stretch to exactly the box size, aspect ratio is not preserved.
+    Exact,
+    /// Scale to fit inside the box, preserving aspect ratio.
+    Fit,
+    /// Scale to fit inside the box, images smaller than the box are not upscaled.
+    Inside,
+    /// Scale to cover the box and crop the overflow from the center. Alias: `crop`.
+    Cover,
+    /// Scale to fit inside the box and letterbox onto the background color. Alias: `contain`.
+    Pad,
+}
+
+/// Edge or corner of the image kept when cropping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gravity {
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+    /// Content aware crop keeping the most detailed area and skin tones.
+    Smart,
+}
+
+/// Point of interest in normalised coordinates, `0.0..=1.0` from the top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FocalPoint {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// RGBA color, written as `#rgb`, `#rrggbb` or `#rrggbbaa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color(pub [u8; 4]);
+
+impl ThumbnailOptions {
+    /// Storage folder name for thumbnails of this size, e.g. `100x100`.
+    pub fn dimensions(&self) -> String {
+        format!("{}x{}", self.width, self.height)
+    }
+
+    /// Suffix distinguishing thumbnails of the same size made with different options.
+    /// `None` for the default (exact size) thumbnails.
+    pub fn variant(&self) -> Option<String> {
+        match self.mode {
+            ResizeMode::Exact => None,
+            ResizeMode::Fit => Some("fit".to_owned()),
+            ResizeMode::Inside => Some("inside".to_owned()),
+            ResizeMode::Cover => match (self.focal_point, self.gravity) {
+                (Some(focal_point), _) => {
+                    Some(format!("cover-fp{:.3}x{:.3}", focal_point.x, focal_point.y))
+                }
+                (None, Gravity::Center) => Some("cover".to_owned()),
+                (None, gravity) => Some(format!("cover-{}", gravity.name())),
+            },
+            ResizeMode::Pad => Some(format!("pad-{}", self.background.to_hex())),
+        }
+    }
 }
 
 impl ThumbnailService for ThumbnailCreator {
-    fn make_thumbnail(
-        &self,
-        bytes: impl AsRef<[u8]>,
-    ) -> Result<image::DynamicImage, ThumbnailError> {
+    // EXIF orientation is applied to the pixels. The decoded image carries no metadata,
+    // so stored thumbnails are upright and metadata free.
+    fn load_image(&self, bytes: impl AsRef<[u8]>) -> Result<image::DynamicImage, ThumbnailError> {
         let img = image::load_from_memory(bytes.as_ref()).map_err(|err| {
             debug!("error while parsing image: {}", err);
             ThumbnailError::InvalidImage(err)
         })?;
-        // if current dimensions are less than required, image is scaled up.
-        if img.width() != self.opt.width || img.height() != self.opt.height {
-            return Ok(self.resize_image(img));
+        Ok(match orientation::exif_orientation(bytes.as_ref()) {
+            Some(exif_orientation) => orientation::apply_orientation(img, exif_orientation),
+            None => img,
+        })
+    }
+
+    fn make_thumbnail(
+        &self,
+        img: &image::DynamicImage,
+        opt: &ThumbnailOptions,
+    ) -> image::DynamicImage {
+        // if current dimensions are less than required, image is scaled up (except `Inside` mode).
+        if img.width() != opt.width || img.height() != opt.height {
+            return self.resize_image(img, opt);
         }
-        Ok(img)
+        img.clone()
+    }
+
+    fn encode_thumbnail(
+        &self,
+        img: &image::DynamicImage,
+        opt: &ThumbnailOptions,
+    ) -> Result<Vec<u8>, ThumbnailError> {
+        encoder::encode(img, opt.format, &opt.encoder).map_err(|err| {
+            error!("error while encoding thumbnail: {}", err);
+            ThumbnailError::EncodeError(err)
+        })
     }
 }
 
 impl ThumbnailCreator {
-    pub fn new(options: ThumbnailOptions) -> Self {
-        ThumbnailCreator { opt: options }
+    pub fn new() -> Self {
+        ThumbnailCreator
     }
 
-    fn resize_image(&self, img: image::DynamicImage) -> image::DynamicImage {
-        let thumbnail = if self.opt.exact_size {
-            img.thumbnail_exact(self.opt.width, self.opt.height)
-        } else {
-            img.thumbnail(self.opt.width, self.opt.height)
+    fn resize_image(
+        &self,
+        img: &image::DynamicImage,
+        opt: &ThumbnailOptions,
+    ) -> image::DynamicImage {
+        let thumbnail = match opt.mode {
+            ResizeMode::Exact => img.thumbnail_exact(opt.width, opt.height),
+            ResizeMode::Fit => img.thumbnail(opt.width, opt.height),
+            ResizeMode::Inside => {
+                if img.width() <= opt.width && img.height() <= opt.height {
+                    img.clone()
+                } else {
+                    img.thumbnail(opt.width, opt.height)
+                }
+            }
+            ResizeMode::Cover => {
+                let (width, height) = cover_dimensions(img.dimensions(), (opt.width, opt.height));
+                let mut scaled = img.thumbnail_exact(width, height);
+                let (x, y) = match (opt.focal_point, opt.gravity) {
+                    (None, Gravity::Smart) => {
+                        smart_crop::crop_offset(&scaled, opt.width, opt.height)
+                    }
+                    _ => crop_offset((width, height), opt),
+                };
+                scaled.crop(x, y, opt.width, opt.height)
+            }
+            ResizeMode::Pad => {
+                let scaled = img.thumbnail(opt.width, opt.height);
+                let mut canvas = image::RgbaImage::from_pixel(
+                    opt.width,
+                    opt.height,
+                    image::Rgba(opt.background.0),
+                );
+                image::imageops::overlay(
+                    &mut canvas,
+                    &scaled.to_rgba(),
+                    (opt.width - scaled.width()) / 2,
+                    (opt.height - scaled.height()) / 2,
+                );
+                image::DynamicImage::ImageRgba8(canvas)
+            }
         };
         return thumbnail;
     }
 }
 
+/// Smallest size with the source aspect ratio that covers the whole box.
+fn cover_dimensions(
+    (width, height): (u32, u32),
+    (box_width, box_height): (u32, u32),
+) -> (u32, u32) {
+    let width_ratio = f64::from(box_width) / f64::from(width);
+    let height_ratio = f64::from(box_height) / f64::from(height);
+    let ratio = width_ratio.max(height_ratio);
+    (
+        ((f64::from(width) * ratio).round() as u32).max(box_width),
+        ((f64::from(height) * ratio).round() as u32).max(box_height),
+    )
+}
+
+/// Top left corner of the `opt.width`x`opt.height` window cropped from the scaled image.
+fn crop_offset((width, height): (u32, u32), opt: &ThumbnailOptions) -> (u32, u32) {
+    let (excess_x, excess_y) = (width - opt.width, height - opt.height);
+    if let Some(focal_point) = opt.focal_point {
+        let offset = |size: u32, excess: u32, box_size: u32, position: f64| {
+            let center = f64::from(size) * position;
+            (center - f64::from(box_size) / 2.0)
+                .round()
+                .max(0.0)
+                .min(f64::from(excess)) as u32
+        };
+        return (
+            offset(width, excess_x, opt.width, focal_point.x),
+            offset(height, excess_y, opt.height, focal_point.y),
+        );
+    }
+    let x = match opt.gravity {
+        Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+        Gravity::East | Gravity::NorthEast | Gravity::SouthEast => excess_x,
+        _ => excess_x / 2,
+    };
+    let y = match opt.gravity {
+        Gravity::North | Gravity::NorthWest | Gravity::NorthEast => 0,
+        Gravity::South | Gravity::SouthWest | Gravity::SouthEast => excess_y,
+        _ => excess_y / 2,
+    };
+    (x, y)
+}
+
+impl FromStr for ResizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(ResizeMode::Exact),
+            "fit" => Ok(ResizeMode::Fit),
+            "inside" => Ok(ResizeMode::Inside),
+            "cover" | "crop" => Ok(ResizeMode::Cover),
+            "pad" | "contain" => Ok(ResizeMode::Pad),
+            _ => Err(format!("unknown resize mode '{}'", s)),
+        }
+    }
+}
+
+// Deserialized from string, so aliases work in configuration too.
+impl<'de> Deserialize<'de> for ResizeMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Gravity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Gravity::Center => "center",
+            Gravity::North => "north",
+            Gravity::NorthEast => "north_east",
+            Gravity::East => "east",
+            Gravity::SouthEast => "south_east",
+            Gravity::South => "south",
+            Gravity::SouthWest => "south_west",
+            Gravity::West => "west",
+            Gravity::NorthWest => "north_west",
+            Gravity::Smart => "smart",
+        }
+    }
+}
+
+impl FromStr for Gravity {
+    type Err = String;
+
+    // accepts `south_east`, `south-east`, `southeast`, `se` and `centre` spellings.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalized = s.to_lowercase().replace(&['_', '-'][..], "");
+        match normalized.as_str() {
+            "center" | "centre" | "c" => Ok(Gravity::Center),
+            "north" | "n" => Ok(Gravity::North),
+            "northeast" | "ne" => Ok(Gravity::NorthEast),
+            "east" | "e" => Ok(Gravity::East),
+            "southeast" | "se" => Ok(Gravity::SouthEast),
+            "south" | "s" => Ok(Gravity::South),
+            "southwest" | "sw" => Ok(Gravity::SouthWest),
+            "west" | "w" => Ok(Gravity::West),
+            "northwest" | "nw" => Ok(Gravity::NorthWest),
+            "smart" | "auto" => Ok(Gravity::Smart),
+            _ => Err(format!("unknown gravity '{}'", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Gravity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl FocalPoint {
+    pub fn is_valid(self) -> bool {
+        (0.0..=1.0).contains(&self.x) && (0.0..=1.0).contains(&self.y)
+    }
+
+    /// Rounds coordinates to the precision used in thumbnail file names,
+    /// so equal file names always mean equal crops.
+    pub fn rounded(self) -> Self {
+        FocalPoint {
+            x: (self.x * 1000.0).round() / 1000.0,
+            y: (self.y * 1000.0).round() / 1000.0,
+        }
+    }
+}
+
+impl Color {
+    pub fn to_hex(self) -> String {
+        let [r, g, b, a] = self.0;
+        if a == 0xff {
+            format!("{:02x}{:02x}{:02x}", r, g, b)
+        } else {
+            format!("{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim_start_matches('#');
+        let digits = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| format!("invalid color '{}'", s))?;
+        let channels = match digits.len() {
+            3 => vec![digits[0] * 17, digits[1] * 17, digits[2] * 17, 0xff],
+            6 | 8 => {
+                let mut channels = digits
+                    .chunks(2)
+                    .map(|pair| pair[0] * 16 + pair[1])
+                    .collect::<Vec<u8>>();
+                channels.resize(4, 0xff);
+                channels
+            }
+            _ => return Err(format!("invalid color '{}'", s)),
+        };
+        Ok(Color([channels[0], channels[1], channels[2], channels[3]]))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.to_hex())
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
 #[derive(Fail, Debug)]
 pub enum ThumbnailError {
     #[fail(display = "Could not parse image: {}", _0)]
     InvalidImage(image::ImageError),
+    #[fail(display = "Could not encode thumbnail: {}", _0)]
+    EncodeError(encoder::EncodeError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(width: u32, height: u32, mode: ResizeMode) -> ThumbnailOptions {
+        ThumbnailOptions {
+            width,
+            height,
+            mode,
+            background: Color([0, 0, 0, 0xff]),
+            gravity: Gravity::Center,
+            focal_point: None,
+            format: encoder::OutputFormat::Jpeg,
+            encoder: encoder::EncoderOptions::default(),
+        }
+    }
+
+    fn source(width: u32, height: u32) -> image::DynamicImage {
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            width,
+            height,
+            image::Rgb([200, 100, 50]),
+        ))
+    }
+
+    #[test]
+    fn test_resize_modes() {
+        let creator = ThumbnailCreator::new();
+        let img = source(400, 200);
+        let cases = vec![
+            (ResizeMode::Exact, (100, 100)),
+            (ResizeMode::Fit, (100, 50)),
+            (ResizeMode::Inside, (100, 50)),
+            (ResizeMode::Cover, (100, 100)),
+            (ResizeMode::Pad, (100, 100)),
+        ];
+        for (mode, expected) in cases {
+            let thumbnail = creator.make_thumbnail(&img, &options(100, 100, mode));
+            assert_eq!(thumbnail.dimensions(), expected, "mode {:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_inside_does_not_upscale() {
+        let creator = ThumbnailCreator::new();
+        let thumbnail =
+            creator.make_thumbnail(&source(40, 20), &options(100, 100, ResizeMode::Inside));
+        assert_eq!(thumbnail.dimensions(), (40, 20));
+        let thumbnail =
+            creator.make_thumbnail(&source(40, 20), &options(100, 100, ResizeMode::Fit));
+        assert_eq!(thumbnail.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn test_pad_background() {
+        let creator = ThumbnailCreator::new();
+        let mut opt = options(100, 100, ResizeMode::Pad);
+        opt.background = "#00ff00".parse().unwrap();
+        let thumbnail = creator.make_thumbnail(&source(400, 200), &opt).to_rgba();
+        assert_eq!(thumbnail.get_pixel(50, 5).data, [0, 0xff, 0, 0xff]);
+        assert_eq!(thumbnail.get_pixel(50, 50).data, [200, 100, 50, 0xff]);
+    }
+
+    // left half red, right half blue, top half bright, bottom half dark.
+    fn quadrants(width: u32, height: u32) -> image::DynamicImage {
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            let level = if y < height / 2 { 255 } else { 100 };
+            if x < width / 2 {
+                image::Rgb([level, 0, 0])
+            } else {
+                image::Rgb([0, 0, level])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_cover_gravity() {
+        let creator = ThumbnailCreator::new();
+        let img = quadrants(400, 200);
+        let mut opt = options(100, 100, ResizeMode::Cover);
+        let cases = vec![
+            (Gravity::West, [255, 0, 0]),
+            (Gravity::East, [0, 0, 255]),
+            (Gravity::SouthWest, [255, 0, 0]),
+        ];
+        for (gravity, expected) in cases {
+            opt.gravity = gravity;
+            let thumbnail = creator.make_thumbnail(&img, &opt).to_rgb();
+            assert_eq!(thumbnail.get_pixel(10, 10).data, expected, "{:?}", gravity);
+            assert_eq!(thumbnail.get_pixel(90, 10).data, expected, "{:?}", gravity);
+        }
+
+        let img = quadrants(200, 400);
+        opt.gravity = Gravity::South;
+        let thumbnail = creator.make_thumbnail(&img, &opt).to_rgb();
+        assert_eq!(thumbnail.get_pixel(10, 10).data, [100, 0, 0]);
+        opt.gravity = Gravity::North;
+        let thumbnail = creator.make_thumbnail(&img, &opt).to_rgb();
+        assert_eq!(thumbnail.get_pixel(10, 90).data, [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_cover_focal_point() {
+        let creator = ThumbnailCreator::new();
+        let img = quadrants(400, 200);
+        let mut opt = options(100, 100, ResizeMode::Cover);
+        opt.gravity = Gravity::West;
+        opt.focal_point = Some(FocalPoint { x: 0.9, y: 0.5 });
+        let thumbnail = creator.make_thumbnail(&img, &opt).to_rgb();
+        assert_eq!(thumbnail.get_pixel(10, 10).data, [0, 0, 255]);
+        // focal point at the very edge keeps the crop inside the image.
+        opt.focal_point = Some(FocalPoint { x: 0.0, y: 0.0 });
+        assert_eq!(crop_offset((200, 100), &opt), (0, 0));
+        opt.focal_point = Some(FocalPoint { x: 1.0, y: 1.0 });
+        assert_eq!(crop_offset((200, 100), &opt), (100, 0));
+    }
+
+    #[test]
+    fn test_gravity_parse() {
+        assert_eq!("south-east".parse::<Gravity>(), Ok(Gravity::SouthEast));
+        assert_eq!("north_west".parse::<Gravity>(), Ok(Gravity::NorthWest));
+        assert_eq!("centre".parse::<Gravity>(), Ok(Gravity::Center));
+        assert_eq!("NE".parse::<Gravity>(), Ok(Gravity::NorthEast));
+        assert!("up".parse::<Gravity>().is_err());
+    }
+
+    #[test]
+    fn test_load_image_orientation() {
+        let creator = ThumbnailCreator::new();
+        let bytes = std::fs::read("test_data/in/exif_orientation/orientation_6.jpg").unwrap();
+        let img = creator.load_image(&bytes).unwrap();
+        assert_eq!(img.dimensions(), (48, 32));
+    }
+
+    #[test]
+    fn test_resize_mode_parse() {
+        assert_eq!("crop".parse::<ResizeMode>(), Ok(ResizeMode::Cover));
+        assert_eq!("contain".parse::<ResizeMode>(), Ok(ResizeMode::Pad));
+        assert_eq!("inside".parse::<ResizeMode>(), Ok(ResizeMode::Inside));
+        assert!("stretch".parse::<ResizeMode>().is_err());
+    }
+
+    #[test]
+    fn test_color_parse() {
+        assert_eq!("#fff".parse::<Color>(), Ok(Color([0xff, 0xff, 0xff, 0xff])));
+        assert_eq!(
+            "102030".parse::<Color>(),
+            Ok(Color([0x10, 0x20, 0x30, 0xff]))
+        );
+        assert_eq!(
+            "#10203040".parse::<Color>(),
+            Ok(Color([0x10, 0x20, 0x30, 0x40]))
+        );
+        assert!("#12".parse::<Color>().is_err());
+        assert!("#gggggg".parse::<Color>().is_err());
+    }
 }
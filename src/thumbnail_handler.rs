@@ -1,28 +1,277 @@
 use crate::download;
+use crate::encoder;
+use crate::result_cache::{CachedResult, ResultCache};
+use crate::signature;
+use crate::single_flight::SingleFlight;
+use crate::source_index::{SourceEntry, SourceIndex};
 use crate::storage;
 use crate::thumbnail;
 use actix_web::{error, http, web, HttpRequest, HttpResponse};
+use bytes::Bytes;
 use failure::Fail;
 use futures::future::*;
 use log::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::iter::FromIterator;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ThumbnailRequest {
-    pub urls: Vec<String>,
+    pub urls: Vec<UrlRequest>,
+    /// Thumbnail parameters applied to every url, unless overridden per url.
+    #[serde(flatten)]
+    pub params: ThumbnailParams,
+    /// Named renditions created for every url, unless overridden per url.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub renditions: BTreeMap<String, ThumbnailParams>,
 }
 
+/// Query of the single image endpoint. Parameter names are short to keep urls in
+/// `<img src>` tags compact.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenderQuery {
+    pub url: String,
+    pub preset: Option<String>,
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub mode: Option<thumbnail::ResizeMode>,
+    pub bg: Option<thumbnail::Color>,
+    pub gravity: Option<thumbnail::Gravity>,
+    /// Focal point, a missing coordinate defaults to the center.
+    pub fx: Option<f64>,
+    pub fy: Option<f64>,
+    pub fmt: Option<encoder::OutputFormat>,
+    /// Quality of the lossy output formats.
+    pub q: Option<u8>,
+}
+
+impl RenderQuery {
+    fn params(&self) -> ThumbnailParams {
+        ThumbnailParams {
+            preset: self.preset.clone(),
+            width: self.w,
+            height: self.h,
+            mode: self.mode,
+            background: self.bg,
+            gravity: self.gravity,
+            focal_point: match (self.fx, self.fy) {
+                (None, None) => None,
+                (x, y) => Some(thumbnail::FocalPoint {
+                    x: x.unwrap_or(0.5),
+                    y: y.unwrap_or(0.5),
+                }),
+            },
+            format: self.fmt,
+            encoder: EncoderParams {
+                jpeg_quality: self.q,
+                webp_quality: self.q,
+                avif_quality: self.q,
+                ..EncoderParams::default()
+            },
+        }
+    }
+}
+
+/// Image url, either plain or with its own thumbnail parameters.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UrlRequest {
+    Url(String),
+    WithParams {
+        url: String,
+        #[serde(flatten)]
+        params: ThumbnailParams,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        renditions: BTreeMap<String, ThumbnailParams>,
+    },
+}
+
+impl UrlRequest {
+    pub fn url(&self) -> &str {
+        match self {
+            UrlRequest::Url(url) => url,
+            UrlRequest::WithParams { url, .. } => url,
+        }
+    }
+}
+
+/// Optional thumbnail parameters. Missing values are taken from the preset, if given,
+/// and then fall back to the configured defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThumbnailParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<thumbnail::ResizeMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<thumbnail::Color>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gravity: Option<thumbnail::Gravity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_point: Option<thumbnail::FocalPoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<encoder::OutputFormat>,
+    #[serde(flatten)]
+    pub encoder: EncoderParams,
+}
+
+/// Optional encoder settings, missing values are taken from the preset or the configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EncoderParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jpeg_quality: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jpeg_progressive: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub png_compression: Option<encoder::PngCompression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webp_lossless: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webp_quality: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avif_quality: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avif_speed: Option<u8>,
+}
+
+impl EncoderParams {
+    fn apply(&self, opt: &encoder::EncoderOptions) -> encoder::EncoderOptions {
+        encoder::EncoderOptions {
+            jpeg_quality: self.jpeg_quality.unwrap_or(opt.jpeg_quality),
+            jpeg_progressive: self.jpeg_progressive.unwrap_or(opt.jpeg_progressive),
+            png_compression: self.png_compression.unwrap_or(opt.png_compression),
+            webp_lossless: self.webp_lossless.unwrap_or(opt.webp_lossless),
+            webp_quality: self.webp_quality.unwrap_or(opt.webp_quality),
+            avif_quality: self.avif_quality.unwrap_or(opt.avif_quality),
+            avif_speed: self.avif_speed.unwrap_or(opt.avif_speed),
+        }
+    }
+}
+
+impl ThumbnailParams {
+    /// Resolves parameters on top of `opt`: preset values first, then explicitly given ones.
+    fn resolve(
+        &self,
+        opt: &thumbnail::ThumbnailOptions,
+        handler_options: &HandlerOptions,
+    ) -> Result<thumbnail::ThumbnailOptions, HandlerError> {
+        if !handler_options.allow_custom_sizes && self.is_custom() {
+            return Err(HandlerError::CustomSizeNotAllowed);
+        }
+        match &self.preset {
+            Some(name) => {
+                let preset = handler_options
+                    .presets
+                    .get(name)
+                    .ok_or_else(|| HandlerError::UnknownPreset(name.clone()))?;
+                Ok(self.apply(&preset.apply(opt)))
+            }
+            None => Ok(self.apply(opt)),
+        }
+    }
+
+    fn apply(&self, opt: &thumbnail::ThumbnailOptions) -> thumbnail::ThumbnailOptions {
+        thumbnail::ThumbnailOptions {
+            width: self.width.unwrap_or(opt.width),
+            height: self.height.unwrap_or(opt.height),
+            mode: self.mode.unwrap_or(opt.mode),
+            background: self.background.unwrap_or(opt.background),
+            gravity: self.gravity.unwrap_or(opt.gravity),
+            // an explicit gravity replaces the focal point inherited from the preset or request.
+            focal_point: match (self.focal_point, self.gravity) {
+                (Some(focal_point), _) => Some(focal_point.rounded()),
+                (None, Some(_)) => None,
+                (None, None) => opt.focal_point,
+            },
+            format: self.format.unwrap_or(opt.format),
+            encoder: self.encoder.apply(&opt.encoder),
+        }
+    }
+
+    fn is_custom(&self) -> bool {
+        self.width.is_some()
+            || self.height.is_some()
+            || self.mode.is_some()
+            || self.background.is_some()
+    }
+}
+
+/// Thumbnails requested for one url. A single unnamed entry is a plain thumbnail,
+/// otherwise entries are named renditions.
+type Renditions = Vec<(Option<String>, thumbnail::ThumbnailOptions)>;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ThumbnailResponse {
-    pub success: HashMap<String, String>,
+    pub success: HashMap<String, ThumbnailResult>,
     pub failed: HashMap<String, String>,
+    /// Urls images were downloaded from, for the urls which were redirected.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub final_urls: HashMap<String, String>,
+}
+
+/// Thumbnail url, or rendition urls keyed by rendition name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ThumbnailResult {
+    Single(String),
+    Renditions(BTreeMap<String, String>),
+}
+
+impl ThumbnailResult {
+    fn from_paths(paths: Vec<(Option<String>, String)>) -> Self {
+        let mut renditions = BTreeMap::new();
+        for (name, path) in paths {
+            match name {
+                Some(name) => {
+                    renditions.insert(name, path);
+                }
+                None => return ThumbnailResult::Single(path),
+            }
+        }
+        ThumbnailResult::Renditions(renditions)
+    }
+
+    fn try_map<E>(self, f: impl Fn(String) -> Result<String, E>) -> Result<Self, E> {
+        Ok(match self {
+            ThumbnailResult::Single(path) => ThumbnailResult::Single(f(path)?),
+            ThumbnailResult::Renditions(paths) => {
+                let mut urls = BTreeMap::new();
+                for (name, path) in paths {
+                    urls.insert(name, f(path)?);
+                }
+                ThumbnailResult::Renditions(urls)
+            }
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct HandlerOptions {
     pub max_url_in_single_req: u64,
+    pub max_thumbnail_dimension: u32,
+    pub default_thumbnail: thumbnail::ThumbnailOptions,
+    pub presets: HashMap<String, ThumbnailParams>,
+    pub allow_custom_sizes: bool,
+    /// Formats stored next to JPEG and PNG thumbnails and served to clients accepting them,
+    /// in order of preference.
+    pub negotiated_formats: Vec<encoder::OutputFormat>,
+    /// Verifies render urls, which must be signed if set.
+    pub signer: Option<signature::UrlSigner>,
+    /// Validators of downloaded images, to download them again only if they changed.
+    pub source_index: SourceIndex,
+    /// Thumbnails made before by image url, to skip downloading the image.
+    pub result_cache: ResultCache,
+    /// Downloads in flight by url and validators.
+    pub downloads: SingleFlight<download::DownloadedImage, download::DownloadError>,
+    /// Thumbnail files being made by their paths, which start with the content hash.
+    pub thumbnails: SingleFlight<(), Arc<HandlerError>>,
 }
 
 pub fn handle<
@@ -38,17 +287,19 @@ pub fn handle<
     http_req: HttpRequest,
 ) -> Box<dyn Future<Item = actix_web::web::Json<ThumbnailResponse>, Error = HandlerError>> {
     Box::new(
-        result(validate_request(&req.urls, &options)).and_then(move |urls| {
+        result(validate_request(&req, &options)).and_then(move |urls| {
             let mut all_futures = vec![];
-            for k in urls {
+            for (k, renditions) in urls {
                 all_futures.push(
                     handle_one_image(
                         thumbnail.clone(),
                         storage.clone(),
                         downloader.clone(),
+                        options.clone(),
                         k.clone(),
+                        renditions,
                     )
-                    .map(move |img_path| (k.clone(), img_path)),
+                    .map(move |img_paths| (k.clone(), img_paths)),
                 );
             }
             join_all(all_futures)
@@ -57,13 +308,21 @@ pub fn handle<
                     let mut response = ThumbnailResponse {
                         success: HashMap::new(),
                         failed: HashMap::new(),
+                        final_urls: HashMap::new(),
                     };
-                    for (key, img_path) in vec {
-                        match img_path {
-                            Ok(path) => {
-                                match http_req.url_for("thumbnail_url", &[path]) {
-                                    Ok(img_url) => {
-                                        response.success.insert(key, img_url.to_string());
+                    for (key, img_paths) in vec {
+                        match img_paths {
+                            Ok((paths, final_url)) => {
+                                if let Some(final_url) = final_url {
+                                    response.final_urls.insert(key.clone(), final_url);
+                                }
+                                match paths.try_map(|path| {
+                                    http_req
+                                        .url_for("thumbnail_url", &[path])
+                                        .map(|img_url| img_url.to_string())
+                                }) {
+                                    Ok(img_urls) => {
+                                        response.success.insert(key, img_urls);
                                     }
                                     Err(err) => {
                                         error!("error while generating url: {}", err);
@@ -84,6 +343,71 @@ pub fn handle<
     )
 }
 
+/// Makes a single thumbnail and responds with the image, or redirects to the stored
+/// thumbnail if it was made before.
+pub fn render<
+    T: thumbnail::ThumbnailService + 'static,
+    S: storage::StorageService + 'static,
+    D: download::DownloadService + 'static,
+>(
+    thumbnail: web::Data<T>,
+    storage: web::Data<S>,
+    downloader: web::Data<D>,
+    options: web::Data<HandlerOptions>,
+    query: web::Query<RenderQuery>,
+    http_req: HttpRequest,
+) -> Box<dyn Future<Item = HttpResponse, Error = HandlerError>> {
+    let query = query.into_inner();
+    let opt = verify_signature(&http_req, &options)
+        .and_then(|_| query.params().resolve(&options.default_thumbnail, &options))
+        .and_then(|opt| validate_thumbnail_options(&opt, &options).map(|_| opt));
+    Box::new(result(opt).and_then(move |opt| {
+        let format = opt.format;
+        download_thumbnails(
+            thumbnail,
+            storage,
+            downloader,
+            options,
+            query.url,
+            vec![(None, opt)],
+        )
+        .and_then(move |(mut thumbnails, _)| {
+            let stored = thumbnails.remove(0);
+            match stored.created {
+                Some(bytes) => Ok(HttpResponse::Ok()
+                    .content_type(format.mime_type())
+                    .body(bytes)),
+                None => http_req
+                    .url_for("thumbnail_url", &[stored.path])
+                    .map(|img_url| {
+                        HttpResponse::Found()
+                            .header(http::header::LOCATION, img_url.to_string())
+                            .finish()
+                    })
+                    .map_err(|err| {
+                        error!("error while generating url: {}", err);
+                        HandlerError::UrlGenerationError(format!("{}", err))
+                    }),
+            }
+        })
+    }))
+}
+
+fn verify_signature(http_req: &HttpRequest, options: &HandlerOptions) -> Result<(), HandlerError> {
+    match &options.signer {
+        Some(signer) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0);
+            signer
+                .verify(http_req.query_string(), now)
+                .map_err(HandlerError::SignatureError)
+        }
+        None => Ok(()),
+    }
+}
+
 fn handle_one_image<
     T: thumbnail::ThumbnailService + 'static,
     S: storage::StorageService + 'static,
@@ -92,66 +416,462 @@ fn handle_one_image<
     thumbnail: web::Data<T>,
     storage: web::Data<S>,
     downloader: web::Data<D>,
+    options: web::Data<HandlerOptions>,
     url: String,
-) -> impl Future<Item = Result<String, HandlerError>, Error = ()> {
+    renditions: Renditions,
+) -> impl Future<Item = Result<(ThumbnailResult, Option<String>), HandlerError>, Error = ()> {
     lazy(move || {
-        downloader
-            .download_image(url)
-            .map_err(|err| HandlerError::DownloadError(err))
-            .and_then(move |bytes| {
-                result(
-                    storage
-                        .get_image_handle(bytes.as_ref())
-                        .map_err(|err| HandlerError::StorageError(err)),
-                )
-                .and_then(|img_handle| {
-                    let ih = img_handle.clone();
-                    lazy(move || {
-                        if ih.exists() {
-                            return ok(Ok(ih.path()));
+        download_thumbnails(thumbnail, storage, downloader, options, url, renditions).map(
+            |(thumbnails, final_url)| {
+                let paths = thumbnails
+                    .into_iter()
+                    .map(|stored| (stored.name, stored.path))
+                    .collect();
+                Ok((ThumbnailResult::from_paths(paths), final_url))
+            },
+        )
+    })
+    .or_else(|err| ok(Err(err)))
+}
+
+/// Thumbnail of one rendition.
+#[derive(Debug, Clone)]
+pub struct StoredThumbnail {
+    name: Option<String>,
+    path: String,
+    /// Encoded thumbnail, if it was made by this request instead of found in storage.
+    created: Option<Vec<u8>>,
+}
+
+/// Downloads an image and makes its missing thumbnails. An image not modified since its
+/// last download is not downloaded again, unless some of its thumbnails are not stored.
+/// Images whose thumbnails are all in the result cache are not requested at all.
+fn download_thumbnails<
+    T: thumbnail::ThumbnailService + 'static,
+    S: storage::StorageService + 'static,
+    D: download::DownloadService + 'static,
+>(
+    thumbnail: web::Data<T>,
+    storage: web::Data<S>,
+    downloader: web::Data<D>,
+    options: web::Data<HandlerOptions>,
+    url: String,
+    renditions: Renditions,
+) -> Box<dyn Future<Item = (Vec<StoredThumbnail>, Option<String>), Error = HandlerError>> {
+    if let Some(cached) = cached_thumbnails(storage.get_ref(), &options, &url, &renditions) {
+        debug!("result cache hit: {}", url);
+        return Box::new(ok(cached));
+    }
+    let result_cache = options.result_cache.clone();
+    let cached_url = url.clone();
+    let cached_renditions = renditions.clone();
+    let known = options.source_index.get(&url);
+    let validators = known.as_ref().map(|entry| entry.validators.clone());
+    Box::new(
+        download_once(&downloader, &options, url.clone(), validators)
+            .and_then(
+                move |image| -> Box<dyn Future<Item = _, Error = HandlerError>> {
+                    if !image.not_modified {
+                        return Box::new(make_downloaded(
+                            thumbnail, storage, options, url, image, renditions,
+                        ));
+                    }
+                    let stored = known.and_then(|entry| {
+                        rendition_handles(
+                            storage.get_ref(),
+                            &options,
+                            &entry.source_hash,
+                            renditions.clone(),
+                        )
+                        .ok()
+                        .and_then(|handles| all_stored(&handles))
+                    });
+                    match stored {
+                        Some(stored) => {
+                            debug!("image not modified, using stored thumbnails: {}", url);
+                            Box::new(ok((stored, image.final_url)))
                         }
-                        return err(());
-                    })
-                    .or_else(|_| {
-                        web::block(move || thumbnail.make_thumbnail(bytes))
-                            .map_err(|err| match err {
-                                error::BlockingError::Error(thumb_err) => {
-                                    HandlerError::ThumbnailError(thumb_err)
-                                }
-                                _ => HandlerError::BlockingCancelled(
-                                    "make thumbnail operation cancelled".to_owned(),
+                        None => {
+                            options.source_index.remove(&url);
+                            Box::new(
+                                download_once(&downloader, &options, url.clone(), None).and_then(
+                                    move |image| {
+                                        make_downloaded(
+                                            thumbnail, storage, options, url, image, renditions,
+                                        )
+                                    },
                                 ),
-                            })
-                            .and_then(|img| {
-                                web::block(move || {
-                                    storage
-                                        .store_image(&img_handle, img)
-                                        .map(move |_| Ok(img_handle.path()))
-                                })
-                                .map_err(|err| match err {
-                                    error::BlockingError::Error(storage_err) => {
-                                        HandlerError::StorageError(storage_err)
-                                    }
-                                    _ => HandlerError::BlockingCancelled(
-                                        "thumbnail store operation cancelled".to_owned(),
-                                    ),
-                                })
-                            })
-                    })
+                            )
+                        }
+                    }
+                },
+            )
+            .map(move |(thumbnails, final_url)| {
+                for (stored, (_, opt)) in thumbnails.iter().zip(&cached_renditions) {
+                    let result = CachedResult {
+                        path: stored.path.clone(),
+                        final_url: final_url.clone(),
+                    };
+                    result_cache.insert(&cached_url, opt, result);
+                }
+                (thumbnails, final_url)
+            }),
+    )
+}
+
+/// Downloads an image, conditionally if `validators` are given, or waits for the same
+/// download started by another request.
+fn download_once<D: download::DownloadService + 'static>(
+    downloader: &web::Data<D>,
+    options: &HandlerOptions,
+    url: String,
+    validators: Option<download::Validators>,
+) -> impl Future<Item = download::DownloadedImage, Error = HandlerError> {
+    let key = format!("{} {:?}", url, validators);
+    let downloader = downloader.clone();
+    options
+        .downloads
+        .run(&key, move || match validators {
+            Some(validators) => downloader.download_if_modified(url, validators),
+            None => downloader.download_image(url),
+        })
+        .map_err(|err| HandlerError::DownloadError(err))
+}
+
+/// Thumbnails of all renditions of `url` from the result cache, if they are still stored.
+fn cached_thumbnails<S: storage::StorageService>(
+    storage: &S,
+    options: &HandlerOptions,
+    url: &str,
+    renditions: &Renditions,
+) -> Option<(Vec<StoredThumbnail>, Option<String>)> {
+    if !options.result_cache.is_enabled() {
+        return None;
+    }
+    let mut thumbnails = vec![];
+    let mut final_url = None;
+    for (name, opt) in renditions {
+        let cached = options.result_cache.get(url, opt)?;
+        let stored = storage
+            .get_stored_handle(&cached.path)
+            .map(|handle| handle.exists())
+            .unwrap_or(false);
+        if !stored {
+            options.result_cache.remove(url, opt);
+            return None;
+        }
+        final_url = cached.final_url;
+        thumbnails.push(StoredThumbnail {
+            name: name.clone(),
+            path: cached.path,
+            created: None,
+        });
+    }
+    Some((thumbnails, final_url))
+}
+
+/// Makes the thumbnails of a downloaded image and remembers its validators.
+fn make_downloaded<
+    T: thumbnail::ThumbnailService + 'static,
+    S: storage::StorageService + 'static,
+>(
+    thumbnail: web::Data<T>,
+    storage: web::Data<S>,
+    options: web::Data<HandlerOptions>,
+    url: String,
+    image: download::DownloadedImage,
+    renditions: Renditions,
+) -> impl Future<Item = (Vec<StoredThumbnail>, Option<String>), Error = HandlerError> {
+    let source_hash = storage.source_hash(image.bytes.as_ref());
+    let source_index = options.source_index.clone();
+    let entry = SourceEntry {
+        validators: image.validators,
+        source_hash: source_hash.clone(),
+    };
+    let final_url = image.final_url;
+    make_thumbnails(
+        thumbnail,
+        storage,
+        options,
+        &source_hash,
+        image.bytes,
+        renditions,
+    )
+    .map(move |thumbnails| {
+        source_index.insert(&url, entry);
+        (thumbnails, final_url)
+    })
+}
+
+/// Storage handles of renditions and of their alternative formats.
+type RenditionHandles = Vec<(
+    Option<String>,
+    thumbnail::ThumbnailOptions,
+    storage::ImageHandle,
+    Vec<(encoder::OutputFormat, storage::ImageHandle)>,
+)>;
+
+fn rendition_handles<S: storage::StorageService>(
+    storage: &S,
+    options: &HandlerOptions,
+    source_hash: &str,
+    renditions: Renditions,
+) -> Result<RenditionHandles, HandlerError> {
+    renditions
+        .into_iter()
+        .map(|(name, opt)| {
+            let formats = alternative_formats(&opt, options);
+            storage
+                .get_image_handle(source_hash, &opt, &formats)
+                .map(|img_handle| {
+                    let alternatives = formats
+                        .into_iter()
+                        .map(|format| (format, storage.get_alternative_handle(&img_handle, format)))
+                        .collect::<Vec<_>>();
+                    (name, opt, img_handle, alternatives)
                 })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| HandlerError::StorageError(err))
+}
+
+/// The stored thumbnails, if all renditions and their alternative formats are stored.
+fn all_stored(handles: &RenditionHandles) -> Option<Vec<StoredThumbnail>> {
+    let all_exist = handles.iter().all(|(_, _, img_handle, alternatives)| {
+        img_handle.exists() && alternatives.iter().all(|(_, alt)| alt.exists())
+    });
+    if !all_exist {
+        return None;
+    }
+    Some(
+        handles
+            .iter()
+            .map(|(name, _, img_handle, _)| StoredThumbnail {
+                name: name.clone(),
+                path: img_handle.path(),
+                created: None,
+            })
+            .collect(),
+    )
+}
+
+/// A missing file of a rendition, in its own format or in an alternative one.
+struct ThumbnailFile {
+    rendition: usize,
+    format: encoder::OutputFormat,
+    handle: storage::ImageHandle,
+}
+
+/// Makes and stores the renditions of a downloaded image which are not stored yet.
+/// Every file is made once for all requests needing it at the same time; a request waits
+/// for the files other requests are making, and finds them stored like files made before.
+fn make_thumbnails<
+    T: thumbnail::ThumbnailService + 'static,
+    S: storage::StorageService + 'static,
+>(
+    thumbnail: web::Data<T>,
+    storage: web::Data<S>,
+    options: web::Data<HandlerOptions>,
+    source_hash: &str,
+    bytes: Bytes,
+    renditions: Renditions,
+) -> impl Future<Item = Vec<StoredThumbnail>, Error = HandlerError> {
+    result(rendition_handles(
+        storage.get_ref(),
+        &options,
+        source_hash,
+        renditions,
+    ))
+    .and_then(move |handles| {
+        if let Some(stored) = all_stored(&handles) {
+            return Either::A(ok(stored));
+        }
+        let mut names = vec![];
+        let mut paths = vec![];
+        let mut renditions = vec![];
+        let mut files = vec![];
+        for (rendition, (name, opt, img_handle, alternatives)) in handles.into_iter().enumerate() {
+            names.push(name);
+            paths.push(img_handle.path());
+            files.extend(
+                std::iter::once((opt.format, img_handle))
+                    .chain(alternatives)
+                    .filter(|(_, handle)| !handle.exists())
+                    .map(|(format, handle)| ThumbnailFile {
+                        rendition,
+                        format,
+                        handle,
+                    }),
+            );
+            renditions.push(opt);
+        }
+        let renditions = Arc::new(renditions);
+        // the files no other request is making are made together once this request runs,
+        // so the source image is decoded once.
+        let led = Rc::new(RefCell::new(Some(vec![])));
+        let made = {
+            let (thumbnail, storage, bytes, renditions, led) = (
+                thumbnail.clone(),
+                storage.clone(),
+                bytes.clone(),
+                renditions.clone(),
+                led.clone(),
+            );
+            lazy(move || {
+                let files = led.borrow_mut().take().unwrap_or_default();
+                store_files(thumbnail, storage, bytes, renditions, files)
+            })
+            .shared()
+        };
+        let flights = files
+            .into_iter()
+            .map(|file| {
+                let key = file.handle.path();
+                let (thumbnail, storage, bytes, renditions, led, made) = (
+                    thumbnail.clone(),
+                    storage.clone(),
+                    bytes.clone(),
+                    renditions.clone(),
+                    led.clone(),
+                    made.clone(),
+                );
+                let make = move || match led.borrow_mut().as_mut() {
+                    Some(led) => {
+                        led.push(file);
+                        Either::A(made.map(|_| ()).map_err(|err| (*err).clone()))
+                    }
+                    // the request making it went away after the others were made.
+                    None => Either::B(
+                        store_files(thumbnail, storage, bytes, renditions, vec![file]).map(|_| ()),
+                    ),
+                };
+                options.thumbnails.run(&key, make)
             })
+            .collect::<Vec<_>>();
+        Either::B(
+            join_all(flights)
+                .and_then(move |_| {
+                    if led.borrow().is_some() {
+                        // every file was made by other requests.
+                        Either::A(ok(None))
+                    } else {
+                        Either::B(made.map(Some).map_err(|err| (*err).clone()))
+                    }
+                })
+                .map(move |made| {
+                    names
+                        .into_iter()
+                        .zip(paths)
+                        .map(|(name, path)| StoredThumbnail {
+                            name,
+                            created: made.as_ref().and_then(|made| made.get(&path).cloned()),
+                            path,
+                        })
+                        .collect()
+                })
+                .map_err(|err| Arc::try_unwrap(err).unwrap_or_else(HandlerError::Shared)),
+        )
     })
-    .or_else(|err| ok(Err(err)))
+}
+
+/// Encodes and stores files of the renditions, decoding the source image once and making
+/// every rendition once. Returns the stored bytes by path.
+fn store_files<T: thumbnail::ThumbnailService + 'static, S: storage::StorageService + 'static>(
+    thumbnail: web::Data<T>,
+    storage: web::Data<S>,
+    bytes: Bytes,
+    renditions: Arc<Vec<thumbnail::ThumbnailOptions>>,
+    files: Vec<ThumbnailFile>,
+) -> impl Future<Item = HashMap<String, Vec<u8>>, Error = Arc<HandlerError>> {
+    web::block(move || {
+        let mut stored = HashMap::new();
+        if files.is_empty() {
+            return Ok(stored);
+        }
+        let img = thumbnail
+            .load_image(bytes)
+            .map_err(|err| HandlerError::ThumbnailError(err))?;
+        let mut thumbnail_imgs = HashMap::new();
+        for file in files {
+            let opt = &renditions[file.rendition];
+            let thumbnail_img = thumbnail_imgs
+                .entry(file.rendition)
+                .or_insert_with(|| thumbnail.make_thumbnail(&img, opt));
+            let format_opt = thumbnail::ThumbnailOptions {
+                format: file.format,
+                ..opt.clone()
+            };
+            let bytes = thumbnail
+                .encode_thumbnail(thumbnail_img, &format_opt)
+                .map_err(|err| HandlerError::ThumbnailError(err))?;
+            storage
+                .store_image(&file.handle, &bytes)
+                .map_err(|err| HandlerError::StorageError(err))?;
+            stored.insert(file.handle.path(), bytes);
+        }
+        Ok(stored)
+    })
+    .map_err(|err| match err {
+        error::BlockingError::Error(handler_err) => handler_err,
+        _ => HandlerError::BlockingCancelled("make thumbnail operation cancelled".to_owned()),
+    })
+    .map_err(Arc::new)
+}
+
+/// Formats a thumbnail is additionally stored in for content negotiation.
+fn alternative_formats(
+    opt: &thumbnail::ThumbnailOptions,
+    handler_options: &HandlerOptions,
+) -> Vec<encoder::OutputFormat> {
+    match opt.format {
+        encoder::OutputFormat::Jpeg | encoder::OutputFormat::Png => {
+            handler_options.negotiated_formats.clone()
+        }
+        _ => vec![],
+    }
 }
 
 fn validate_request(
-    urls: &Vec<String>,
+    req: &ThumbnailRequest,
     handler_options: &HandlerOptions,
-) -> Result<HashSet<String>, HandlerError> {
-    if urls.len() < 1 {
+) -> Result<HashMap<String, Renditions>, HandlerError> {
+    if req.urls.is_empty() {
         return Err(HandlerError::EmptyURLArray);
     }
-    let unique_urls: HashSet<String> = HashSet::from_iter(urls.iter().map(|url| url.to_owned()));
+    let default_opt = req
+        .params
+        .resolve(&handler_options.default_thumbnail, handler_options)?;
+    let mut unique_urls = HashMap::new();
+    for url_req in &req.urls {
+        let renditions = match url_req {
+            UrlRequest::Url(_) => {
+                resolve_renditions(&default_opt, &req.renditions, handler_options)?
+            }
+            UrlRequest::WithParams {
+                params, renditions, ..
+            } => {
+                let url_opt = params.resolve(&default_opt, handler_options)?;
+                if renditions.is_empty() {
+                    resolve_renditions(&url_opt, &req.renditions, handler_options)?
+                } else {
+                    resolve_renditions(&url_opt, renditions, handler_options)?
+                }
+            }
+        };
+        for (_, opt) in &renditions {
+            validate_thumbnail_options(opt, handler_options)?;
+        }
+        // the same url may be listed again, but only asking for the same thumbnails.
+        match unique_urls.get(url_req.url()) {
+            Some(listed) if *listed != renditions => {
+                return Err(HandlerError::ConflictingUrl(url_req.url().to_owned()));
+            }
+            Some(_) => (),
+            None => {
+                unique_urls.insert(url_req.url().to_owned(), renditions);
+            }
+        }
+    }
     if unique_urls.len() as u64 > handler_options.max_url_in_single_req {
         return Err(HandlerError::TooManyURL(
             handler_options.max_url_in_single_req,
@@ -160,6 +880,49 @@ fn validate_request(
     return Ok(unique_urls);
 }
 
+fn resolve_renditions(
+    opt: &thumbnail::ThumbnailOptions,
+    renditions: &BTreeMap<String, ThumbnailParams>,
+    handler_options: &HandlerOptions,
+) -> Result<Renditions, HandlerError> {
+    if renditions.is_empty() {
+        return Ok(vec![(None, opt.clone())]);
+    }
+    renditions
+        .iter()
+        .map(|(name, params)| {
+            params
+                .resolve(opt, handler_options)
+                .map(|rendition_opt| (Some(name.clone()), rendition_opt))
+        })
+        .collect()
+}
+
+fn validate_thumbnail_options(
+    opt: &thumbnail::ThumbnailOptions,
+    handler_options: &HandlerOptions,
+) -> Result<(), HandlerError> {
+    let max = handler_options.max_thumbnail_dimension;
+    if opt.width == 0 || opt.height == 0 || opt.width > max || opt.height > max {
+        return Err(HandlerError::InvalidThumbnailSize(
+            opt.width, opt.height, max,
+        ));
+    }
+    if let Some(focal_point) = opt.focal_point {
+        if !focal_point.is_valid() {
+            return Err(HandlerError::InvalidFocalPoint(
+                focal_point.x,
+                focal_point.y,
+            ));
+        }
+    }
+    // settings of formats which are not encoded don't matter.
+    std::iter::once(opt.format)
+        .chain(alternative_formats(opt, handler_options))
+        .try_for_each(|format| opt.encoder.validate_format(format))
+        .map_err(HandlerError::InvalidEncoderOptions)
+}
+
 #[derive(Fail, Debug)]
 pub enum HandlerError {
     #[fail(display = "not reachable error")]
@@ -168,6 +931,31 @@ pub enum HandlerError {
     EmptyURLArray,
     #[fail(display = "Request contains more than {} unique urls", _0)]
     TooManyURL(u64),
+    #[fail(
+        display = "Invalid thumbnail size {}x{}, width and height must be between 1 and {}",
+        _0, _1, _2
+    )]
+    InvalidThumbnailSize(u32, u32, u32),
+    #[fail(
+        display = "Invalid focal point {}x{}, coordinates must be between 0 and 1",
+        _0, _1
+    )]
+    InvalidFocalPoint(f64, f64),
+    #[fail(display = "Invalid encoder options: {}", _0)]
+    InvalidEncoderOptions(String),
+    #[fail(
+        display = "Url '{}' is listed more than once with different thumbnail parameters",
+        _0
+    )]
+    ConflictingUrl(String),
+    #[fail(display = "Unknown thumbnail preset '{}'", _0)]
+    UnknownPreset(String),
+    #[fail(display = "Custom thumbnail sizes are not allowed, use a preset")]
+    CustomSizeNotAllowed,
+    #[fail(display = "{}", _0)]
+    SignatureError(signature::SignatureError),
+    #[fail(display = "Could not generate thumbnail url: {}", _0)]
+    UrlGenerationError(String),
     #[fail(display = "Could not download image: {}", _0)]
     DownloadError(download::DownloadError),
     #[fail(display = "Operation cancelled: {}", _0)]
@@ -176,15 +964,51 @@ pub enum HandlerError {
     ThumbnailError(thumbnail::ThumbnailError),
     #[fail(display = "Storage error: {}", _0)]
     StorageError(storage::StorageError),
+    /// Error of an operation shared with other requests.
+    #[fail(display = "{}", _0)]
+    Shared(Arc<HandlerError>),
 }
 
 impl error::ResponseError for HandlerError {
     fn error_response(&self) -> HttpResponse {
         match self {
-            HandlerError::EmptyURLArray | HandlerError::TooManyURL(_) => {
+            HandlerError::EmptyURLArray
+            | HandlerError::TooManyURL(_)
+            | HandlerError::InvalidThumbnailSize(..)
+            | HandlerError::InvalidFocalPoint(..)
+            | HandlerError::InvalidEncoderOptions(_)
+            | HandlerError::ConflictingUrl(_)
+            | HandlerError::UnknownPreset(_)
+            | HandlerError::CustomSizeNotAllowed => {
                 HttpResponse::new(http::StatusCode::BAD_REQUEST)
             }
+            HandlerError::SignatureError(_) => HttpResponse::new(http::StatusCode::FORBIDDEN),
+            HandlerError::DownloadError(err) => HttpResponse::new(download_error_status(err)),
+            HandlerError::Shared(err) => err.error_response(),
             _ => HttpResponse::new(http::StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
 }
+
+/// Rejected urls and images are the client's fault, failures of the origin are reported
+/// as a bad gateway.
+fn download_error_status(err: &download::DownloadError) -> http::StatusCode {
+    use download::DownloadError::*;
+    match err {
+        UrsParseError { .. }
+        | ContentLenghtError { .. }
+        | InvalidContentType { .. }
+        | UnrecognizedImage { .. }
+        | InputFormatNotAllowed { .. }
+        | ContentTypeMismatch { .. } => http::StatusCode::BAD_REQUEST,
+        HostNotAllowed { .. } | DestinationNotAllowed { .. } | InsecureRedirect { .. } => {
+            http::StatusCode::FORBIDDEN
+        }
+        FailedGetImage { .. }
+        | StatusCodeNotOK { .. }
+        | FailedParsePayload { .. }
+        | TooManyRedirects { .. } => http::StatusCode::BAD_GATEWAY,
+        Timeout { .. } => http::StatusCode::GATEWAY_TIMEOUT,
+        RetriesExhausted { error, .. } => download_error_status(error),
+    }
+}